@@ -1,20 +1,49 @@
 use std::str::FromStr;
+use std::sync::Arc;
 
 use ataxx::Position;
 
-use crate::mcts::{self, Searcher};
+use crate::mcts::{self, nnue, policy, policy_net, value};
+use crate::options;
+
+/// Searcher is the type of the searcher whose policy and evaluation are
+/// selected with the engine's options.
+pub type Searcher = mcts::Searcher<policy::Selected, value::Selected>;
 
 pub struct Context {
     pub position: Position,
-    pub searcher: mcts::Searcher,
+    pub searcher: Option<Searcher>, // None while a search is running.
+    pub handle: mcts::Handle,       // Handle to control the searcher.
+
+    pub weights: value::Weights, // Weights of the handcrafted evaluation.
+
+    // The networks loaded from the files set in the EvalFile and PolicyFile
+    // options, along with those files.
+    pub eval_file: String,
+    pub network: Arc<nnue::Network>,
+    pub policy_file: String,
+    pub policy_net: Option<Arc<policy_net::Network>>,
 }
 
-impl Default for Context {
-    fn default() -> Self {
+impl Context {
+    /// new creates a Context whose searcher uses the given value network,
+    /// which was loaded from the given EvalFile.
+    pub fn new(eval_file: String, network: Arc<nnue::Network>) -> Context {
         let position = Position::from_str("x5o/7/7/7/7/7/o5x x 0 1").unwrap();
+        let searcher = Searcher::new(
+            position,
+            policy::Selected::Handcrafted(policy::Handcrafted),
+            value::Selected::Network(network.clone()),
+        );
         Context {
             position,
-            searcher: Searcher::new(position, mcts::policy::handcrafted, mcts::value::material),
+            handle: searcher.handle(),
+            searcher: Some(searcher),
+            weights: Default::default(),
+            eval_file,
+            network,
+            policy_file: options::NO_POLICY_FILE.to_string(),
+            policy_net: None,
         }
     }
 }
@@ -0,0 +1,81 @@
+use uxi::{Bundle, Command};
+
+use crate::mcts::{self, value, Policy, Value};
+use crate::options;
+
+use super::go::evaluators;
+use super::Context;
+
+/// eval prints the static evaluation of the current position with the selected
+/// evaluation, along with the breakdown of its terms for the handcrafted one,
+/// and the priors of the legal moves in the position, best first.
+pub fn eval() -> Command<Context> {
+    Command::new(|bundle: Bundle<Context>| {
+        let mut ctx = bundle.lock();
+        let position = ctx.position;
+        let params = options::params(&ctx);
+        let (policy, value) = evaluators(&mut ctx);
+        drop(ctx);
+
+        let eval = value.evaluate(&value.state(&position), &position);
+
+        let evaluation = match value {
+            value::Selected::Network(_) => "network",
+            value::Selected::Handcrafted(_) => "handcrafted",
+            value::Selected::Material(_) => "material",
+        };
+
+        println!("evaluation {}", evaluation);
+        println!(
+            "score cp {:.0} wdl {:.4}",
+            eval,
+            value::eval_to_wdl(eval, params.eval_scale())
+        );
+
+        if let value::Selected::Handcrafted(handcrafted) = value {
+            println!();
+            println!(
+                "{:<10} {:>8} {:>8} {:>8}",
+                "term", "value", "weight", "score"
+            );
+
+            let terms = value::terms(&position);
+            for ((name, term), weight) in
+                value::NAMES.iter().zip(terms).zip(handcrafted.weights.vals)
+            {
+                println!(
+                    "{:<10} {:>8.2} {:>8.2} {:>8.2}",
+                    name,
+                    term,
+                    weight,
+                    term * weight
+                );
+            }
+        }
+
+        // Compute the priors the same way the search expands nodes.
+        let mut edges = mcts::Edges::new();
+        let mut state = Default::default();
+        policy.prepare(&mut state, &params, &position);
+        edges.expand(
+            &position,
+            |mov| policy.logit(&state, &params, &position, mov),
+            params.policy_temperature(),
+        );
+
+        let mut edges: Vec<&mcts::Edge> = edges.iter().collect();
+        edges.sort_by(|a, b| b.policy().partial_cmp(&a.policy()).unwrap());
+
+        println!();
+        println!("{:<6} {:>7}", "move", "prior");
+        for edge in edges {
+            println!(
+                "{:<6} {:>6.2}%",
+                edge.mov.to_string(),
+                edge.policy() * 100.0
+            );
+        }
+
+        Ok(())
+    })
+}
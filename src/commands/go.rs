@@ -1,9 +1,11 @@
-use std::time;
+use std::sync::Arc;
+use std::{thread, time};
 
 use ataxx::Position;
-use uxi::{Bundle, Command, error, Flag, RunError};
+use uxi::{Bundle, BundledCtx, Command, error, Flag, RunError};
 
-use crate::mcts;
+use crate::mcts::{self, nnue, policy, policy_net, value};
+use crate::options;
 
 use super::Context;
 
@@ -34,50 +36,92 @@ pub fn go() -> Command<Context> {
         lock! {
             bundle > ctx =>
             let position = ctx.position; // Get the position to search
-            let mut searcher = ctx.searcher.clone(); // Get the previous search state
         }
 
-        let mut nodes = 0;
-
-        // Update the searcher with the new position and start searching.
-        searcher.update_position(position);
         match parse_limits(&bundle, &position)? {
             // Search flags received, search the position.
             Config::Search(limits) => {
-                let bestmove = searcher.search(limits, &mut nodes);
-
-                println!("bestmove {}", bestmove);
-
                 lock_mutable! {
                     bundle > ctx =>
-                    // Push the new search state to the context.
-                    ctx.searcher = searcher;
+                    // Take the previous search state, instead of cloning it,
+                    // since the search tree may take up a lot of memory.
+                    let searcher = ctx.searcher.take();
+                    let handle = ctx.handle.clone();
+                    let threads = ctx.get_spin_option("Threads").unwrap();
+                    let hash = ctx.get_spin_option("Hash").unwrap();
+                    let overhead = ctx.get_spin_option("MoveOverhead").unwrap();
+                    let multipv = ctx.get_spin_option("MultiPV").unwrap();
+                    let params = options::params(&ctx);
+                    let (policy, value) = evaluators(&mut ctx);
+                    let simulation = simulation(&ctx);
                 }
 
+                let mut searcher = match searcher {
+                    Some(searcher) => searcher,
+                    None => return error!("a search is already in progress"),
+                };
+
+                // Withdraw the signals sent while no search was running. This
+                // is done before the Command returns, so that a stop sent right
+                // after it is received even if the search hasn't started yet.
+                handle.clear();
+
+                // Search in a separate thread so that the Client can still
+                // respond to and run other Commands while it is running.
+                thread::spawn(move || {
+                    searcher.set_threads(threads as usize);
+                    searcher.set_hash(hash as usize);
+                    searcher.set_move_overhead(overhead as u128);
+                    searcher.set_multipv(multipv as usize);
+                    searcher.set_params(params);
+                    searcher.set_policy(policy);
+                    searcher.set_value(value);
+                    searcher.set_simulation(simulation);
+
+                    // Update the searcher with the new position and start searching.
+                    let mut nodes = 0;
+                    searcher.update_position(position);
+                    let bestmove = searcher.search(limits, &mut nodes);
+
+                    match searcher.ponder_move() {
+                        Some(ponder) => println!("bestmove {} ponder {}", bestmove, ponder),
+                        None => println!("bestmove {}", bestmove),
+                    }
+
+                    lock_mutable! {
+                        bundle > ctx =>
+                        // Push the new search state to the context.
+                        ctx.searcher = Some(searcher);
+                    }
+                });
+
                 Ok(())
             }
 
-            // Perft flags received, run a perft on the position.
+            // Perft flags received, run a perft on the position in a separate
+            // thread, like a search.
             Config::Perft(bulk, max_depth) => {
-                for depth in 1..=max_depth {
-                    let start = time::Instant::now();
-                    let nodes = if bulk {
-                        ataxx::perft::<false, true>(position, depth)
-                    } else {
-                        ataxx::perft::<false, false>(position, depth)
-                    };
-                    let duration = start.elapsed();
-
-                    let time = duration.as_millis().max(1);
-
-                    println!(
-                        "info depth {} nodes {} time {} nps {}",
-                        depth,
-                        nodes,
-                        time,
-                        1000 * nodes as u128 / time
-                    );
-                }
+                thread::spawn(move || {
+                    for depth in 1..=max_depth {
+                        let start = time::Instant::now();
+                        let nodes = if bulk {
+                            ataxx::perft::<false, true>(position, depth)
+                        } else {
+                            ataxx::perft::<false, false>(position, depth)
+                        };
+                        let duration = start.elapsed();
+
+                        let time = duration.as_millis().max(1);
+
+                        println!(
+                            "info depth {} nodes {} time {} nps {}",
+                            depth,
+                            nodes,
+                            time,
+                            1000 * nodes as u128 / time
+                        );
+                    }
+                });
 
                 Ok(())
             }
@@ -94,14 +138,115 @@ pub fn go() -> Command<Context> {
     .flag("nodes", Flag::Single)
     .flag("movetime", Flag::Single)
     // Flags for setting the search type.
-    // .flag("ponder", Flag::Single)
-    .flag("infinite", Flag::Single)
+    .flag("ponder", Flag::Boolean)
+    .flag("infinite", Flag::Boolean)
     // Flags for go perft command.
     .flag("perft", Flag::Single)
     .flag("bulk", Flag::Boolean)
-    // This command should be run in a separate thread so that the Client
-    // can still respond to and run other Commands while this one is running.
-    .parallelize(true)
+}
+
+/// evaluators returns the policy and the evaluation selected by the options,
+/// falling back to the value network if the selected evaluation is unknown.
+pub(super) fn evaluators(ctx: &mut BundledCtx<Context>) -> (policy::Selected, value::Selected) {
+    let (network, policy_net) = load_networks(ctx);
+
+    let policy = match policy_net {
+        Some(policy_net) => policy::Selected::Network(policy_net),
+        None => policy::Selected::Handcrafted(policy::Handcrafted),
+    };
+
+    let evaluation = ctx.get_string_option("Evaluation").unwrap();
+    let value = match evaluation.as_str() {
+        "handcrafted" => value::Selected::Handcrafted(value::Handcrafted {
+            weights: ctx.weights,
+        }),
+        "material" => value::Selected::Material(value::Material),
+        _ => {
+            if evaluation != "network" {
+                println!("info string error: unknown evaluation {:?}", evaluation);
+            }
+
+            value::Selected::Network(network)
+        }
+    };
+
+    (policy, value)
+}
+
+/// simulation returns the simulation strategy selected by the options, falling
+/// back to evaluating the leaves if the selected strategy is unknown.
+fn simulation(ctx: &BundledCtx<Context>) -> mcts::Simulation {
+    let simulation = ctx.get_string_option("Simulation").unwrap();
+    match simulation.as_str() {
+        "random" => mcts::Simulation::Random,
+        "policy" => mcts::Simulation::Policy,
+        "truncated" => {
+            let moves = ctx.get_spin_option("PlayoutMoves").unwrap();
+            mcts::Simulation::Truncated(moves as usize)
+        }
+        _ => {
+            if simulation != "eval" {
+                println!("info string error: unknown simulation {:?}", simulation);
+            }
+
+            mcts::Simulation::Evaluate
+        }
+    }
+}
+
+/// load_networks returns the value and policy networks selected by the options,
+/// loading them again if their files were changed since they were last loaded.
+/// If a network can't be loaded, the previously loaded one is used instead and
+/// the error is reported to the GUI, as errors from parallel commands aren't.
+fn load_networks(
+    ctx: &mut BundledCtx<Context>,
+) -> (Arc<nnue::Network>, Option<Arc<policy_net::Network>>) {
+    let eval_file = ctx.get_string_option("EvalFile").unwrap();
+    if eval_file != ctx.eval_file {
+        let network = if eval_file == options::DEFAULT_EVAL_FILE {
+            Ok(nnue::Network::embedded())
+        } else {
+            nnue::Network::load(&eval_file)
+        };
+
+        match network {
+            Ok(network) => {
+                ctx.network = Arc::new(network);
+                ctx.eval_file = eval_file;
+            }
+            Err(err) => println!("info string error: {}", err),
+        }
+    }
+
+    let policy_file = ctx.get_string_option("PolicyFile").unwrap();
+    if policy_file != ctx.policy_file {
+        let policy_net = if policy_file == options::NO_POLICY_FILE {
+            Ok(None)
+        } else {
+            policy_net::Network::load(&policy_file).map(|net| Some(Arc::new(net)))
+        };
+
+        match policy_net {
+            Ok(policy_net) => {
+                ctx.policy_net = policy_net;
+                ctx.policy_file = policy_file;
+            }
+            Err(err) => println!("info string error: {}", err),
+        }
+    }
+
+    // The policy network is only used if it has been enabled.
+    let policy_net = if ctx.get_check_option("PolicyNet").unwrap() {
+        if ctx.policy_net.is_none() {
+            println!("info string error: PolicyNet is enabled without a PolicyFile");
+        }
+
+        ctx.policy_net.clone()
+    } else {
+        None
+    };
+
+    (ctx.network.clone(), policy_net)
 }
 
 enum Config {
@@ -125,7 +270,8 @@ fn parse_limits(bundle: &Bundle<Context>, position: &Position) -> Result<Config,
     let nodes = bundle.is_flag_set("nodes");
     let movetime = bundle.is_flag_set("movetime");
 
-    // Infinite flag
+    // Search type flags
+    let ponder = bundle.is_flag_set("ponder");
     let infinite = bundle.is_flag_set("infinite");
 
     // Perft flags
@@ -159,6 +305,11 @@ fn parse_limits(bundle: &Bundle<Context>, position: &Position) -> Result<Config,
         return error!("bad flag set: time control flags set alongside perft");
     }
 
+    // The ponder flag can't be set alongside perft.
+    if perft && ponder {
+        return error!("bad flag set: ponder flag set alongside perft");
+    }
+
     // A little utility macro to parse the given flag into the required type.
     macro_rules! get_flag {
         ($name:expr) => {
@@ -176,24 +327,21 @@ fn parse_limits(bundle: &Bundle<Context>, position: &Position) -> Result<Config,
     if perft {
         Ok(Config::Perft(bulk, get_flag!("perft").unwrap()))
     } else {
+        // Find the time control flags for the side to move.
+        let (time, incr) = match position.side_to_move {
+            ataxx::Piece::Black => ("btime", "binc"),
+            ataxx::Piece::White => ("wtime", "winc"),
+            _ => unreachable!(),
+        };
+
         Ok(Config::Search(mcts::Limits {
             maxnodes: get_flag!("nodes"),
             maxdepth: get_flag!("depth"),
-            movetime: if std_tc {
-                let (time, incr) = match position.side_to_move {
-                    ataxx::Piece::Black => ("btime", "binc"),
-                    ataxx::Piece::White => ("wtime", "winc"),
-                    _ => unreachable!(),
-                };
-
-                let time: u128 = get_flag!(time).unwrap();
-                let incr: u128 = get_flag!(incr).unwrap();
-
-                Some((time / 20 + incr / 2).max(1))
-            } else {
-                get_flag!("movetime")
-            },
+            movetime: get_flag!("movetime"),
+            time: get_flag!(time),
+            incr: get_flag!(incr),
             movestogo: get_flag!("movestogo"),
+            ponder,
         }))
     }
 }
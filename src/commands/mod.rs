@@ -1,9 +1,11 @@
 pub use context::*;
+pub use eval::*;
 pub use go::*;
 pub use position::*;
 pub use simple::*;
 
 mod context;
+mod eval;
 mod go;
 mod position;
 mod simple;
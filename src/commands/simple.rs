@@ -3,6 +3,8 @@ use std::str::FromStr;
 use ataxx::Position;
 use uxi::Command;
 
+use crate::{options, tune};
+
 use super::Context;
 
 pub fn d() -> Command<Context> {
@@ -19,6 +21,29 @@ pub fn uainewgame() -> Command<Context> {
         let mut ctx = bundle.lock();
         ctx.position = Position::from_str("x5o/7/7/7/7/7/o5x x 0 1")?;
 
+        // Values stored in the previous game shouldn't leak into the new one.
+        if let Some(searcher) = &mut ctx.searcher {
+            searcher.clear_table();
+        }
+
+        Ok(())
+    })
+}
+
+pub fn stop() -> Command<Context> {
+    Command::new(|bundle| {
+        let ctx = bundle.lock();
+        ctx.handle.stop();
+
+        Ok(())
+    })
+}
+
+pub fn ponderhit() -> Command<Context> {
+    Command::new(|bundle| {
+        let ctx = bundle.lock();
+        ctx.handle.ponderhit();
+
         Ok(())
     })
 }
@@ -30,3 +55,12 @@ pub fn protocol() -> Command<Context> {
         Ok(())
     })
 }
+
+pub fn tune() -> Command<Context> {
+    Command::new(|bundle| {
+        let ctx = bundle.lock();
+        print!("{}", tune::spsa_input(&options::params(&ctx)));
+
+        Ok(())
+    })
+}
@@ -0,0 +1,271 @@
+//! data implements the text format of the labelled positions used to tune and
+//! train the evaluation. Every line contains a position's FEN, the score of
+//! the position, in centipawns, and the result of the game it was taken from,
+//! as 1.0 for a win, 0.5 for a draw and 0.0 for a loss, separated by '|'s:
+//!
+//!     x5o/7/7/7/7/7/o5x x 0 1 | 32 | 1.0
+//!
+//! Both the score and the result are from the perspective of the side to move
+//! in the position. The score is optional and may be left out of a line.
+//! A line may also end with the visit distribution of the position's search,
+//! as a space separated list of moves and their visits:
+//!
+//!     x5o/7/7/7/7/7/o5x x 0 1 | 32 | 1.0 | b6:812 f2:790 a1c3:14
+//!
+//! The positions generated through self-play are stored as [Record]s in a more
+//! compact binary format, which can be dumped to the text format.
+use std::io::{self, Read, Write};
+use std::str::FromStr;
+use std::{fmt, fs};
+
+use ataxx::{BitBoard, Move, Piece, Square};
+
+/// Entry is a position labelled with the result of its game, and its score
+/// and visit distribution if the line had them. The visits are empty if not.
+#[derive(Clone)]
+pub struct Entry {
+    pub position: ataxx::Position,
+    pub score: Option<f64>,
+    pub result: f64,
+    pub visits: Vec<(Move, u16)>,
+}
+
+/// read reads all the labelled positions from the given text file.
+pub fn read(path: &str) -> Result<Vec<Entry>, String> {
+    let file = fs::read_to_string(path).map_err(|err| format!("{}: {}", path, err))?;
+
+    file.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| parse(line).map_err(|err| format!("{}:{}: {}", path, i + 1, err)))
+        .collect()
+}
+
+/// parse parses a labelled position from the given line.
+pub fn parse(line: &str) -> Result<Entry, String> {
+    let fields: Vec<&str> = line.split('|').map(str::trim).collect();
+
+    let (fen, score, result, visits) = match fields[..] {
+        [fen, result] => (fen, None, result, ""),
+        [fen, score, result] => (fen, Some(score), result, ""),
+        [fen, score, result, visits] => (fen, Some(score), result, visits),
+        _ => return Err("expected 2 to 4 fields".to_string()),
+    };
+
+    let position = ataxx::Position::from_str(fen).map_err(|err| err.to_string())?;
+
+    let score = match score {
+        Some(score) => Some(
+            score
+                .parse()
+                .map_err(|_| format!("bad score {:?}", score))?,
+        ),
+        None => None,
+    };
+
+    let result = match result.parse() {
+        Ok(result) if (0.0..=1.0).contains(&result) => result,
+        _ => return Err(format!("bad result {:?}", result)),
+    };
+
+    let visits = visits
+        .split_whitespace()
+        .map(|visit| {
+            let parsed = visit
+                .split_once(':')
+                .and_then(|(mov, visits)| Some((mov.parse().ok()?, visits.parse().ok()?)));
+            parsed.ok_or_else(|| format!("bad visits {:?}", visit))
+        })
+        .collect::<Result<_, _>>()?;
+
+    Ok(Entry {
+        position,
+        score,
+        result,
+        visits,
+    })
+}
+
+/// Record is a position generated through self-play, along with the score and
+/// visit distribution of its search and the result of its game, all from the
+/// perspective of the side to move.
+pub struct Record {
+    pub position: ataxx::Position,
+    pub score: i16,
+    pub result: f64,
+    pub visits: Vec<(Move, u16)>,
+}
+
+impl Record {
+    /// write serializes the Record to the given writer. The black, white and
+    /// blocker bitboards are written first, then the side to move, half-move
+    /// clock, ply count, score, result and the number of visited moves, each
+    /// of which is then written as its source, target and visits. All values
+    /// are little endian, with the result as the number of half-points won
+    /// and a pass having both its source and target as [Square::N].
+    pub fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let position = &self.position;
+
+        let mut bytes = Vec::with_capacity(33 + 4 * self.visits.len());
+        for piece in [Piece::Black, Piece::White, Piece::Block] {
+            bytes.extend(u64::from(position.bitboard(piece)).to_le_bytes());
+        }
+
+        bytes.push(position.side_to_move as u8);
+        bytes.push(position.half_move_clock);
+        bytes.extend(position.ply_count.to_le_bytes());
+        bytes.extend(self.score.to_le_bytes());
+        bytes.push((self.result * 2.0).round() as u8);
+        bytes.extend((self.visits.len() as u16).to_le_bytes());
+
+        for &(mov, visits) in &self.visits {
+            let (source, target) = if mov == Move::PASS {
+                (Square::N as u8, Square::N as u8)
+            } else {
+                (mov.source() as u8, mov.target() as u8)
+            };
+
+            bytes.extend([source, target]);
+            bytes.extend(visits.to_le_bytes());
+        }
+
+        writer.write_all(&bytes)
+    }
+
+    /// read deserializes the next Record from the given reader, returning None
+    /// if the reader has no more Records.
+    pub fn read<R: Read>(reader: &mut R) -> io::Result<Option<Record>> {
+        let mut header = [0; 33];
+        match reader.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(err),
+        }
+
+        let u64_at = |i: usize| u64::from_le_bytes(header[i..i + 8].try_into().unwrap());
+        let u16_at = |i: usize| u16::from_le_bytes([header[i], header[i + 1]]);
+
+        let side_to_move = match header[24] {
+            0 => Piece::Black,
+            1 => Piece::White,
+            stm => return Err(invalid(format!("bad side to move {}", stm))),
+        };
+
+        let position = ataxx::Position::new(
+            BitBoard(u64_at(0)),
+            BitBoard(u64_at(8)),
+            BitBoard(u64_at(16)),
+            side_to_move,
+            u16_at(26),
+            header[25],
+        );
+
+        let score = u16_at(28) as i16;
+        let result = match header[30] {
+            result @ 0..=2 => result as f64 / 2.0,
+            result => return Err(invalid(format!("bad result {}", result))),
+        };
+
+        let mut moves = vec![0; 4 * u16_at(31) as usize];
+        reader.read_exact(&mut moves)?;
+
+        let visits = moves
+            .chunks_exact(4)
+            .map(|chunk| {
+                let mov = if chunk[0] as usize == Square::N {
+                    Move::PASS
+                } else {
+                    match (square(chunk[0]), square(chunk[1])) {
+                        (Some(source), Some(target)) => Move::new(source, target),
+                        _ => return Err(invalid("bad move".to_string())),
+                    }
+                };
+
+                Ok((mov, u16::from_le_bytes([chunk[2], chunk[3]])))
+            })
+            .collect::<io::Result<_>>()?;
+
+        Ok(Some(Record {
+            position,
+            score,
+            result,
+            visits,
+        }))
+    }
+}
+
+/// Record is displayed as a line of the text format, followed by the visit
+/// distribution as a space separated list of moves and their visits.
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} | {} | {:.1} |",
+            fen(&self.position),
+            self.score,
+            self.result
+        )?;
+
+        for (mov, visits) in &self.visits {
+            write!(f, " {}:{}", mov, visits)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// fen returns the FEN of the given position.
+pub fn fen(position: &ataxx::Position) -> String {
+    let mut fen = String::new();
+
+    for rank in (0..7).rev() {
+        let mut empty = 0;
+        for file in 0..7 {
+            let symbol = match position.at(square(rank * 7 + file).unwrap()) {
+                Piece::Black => 'x',
+                Piece::White => 'o',
+                Piece::Block => '-',
+                _ => {
+                    empty += 1;
+                    continue;
+                }
+            };
+
+            if empty > 0 {
+                fen.push_str(&empty.to_string());
+                empty = 0;
+            }
+
+            fen.push(symbol);
+        }
+
+        if empty > 0 {
+            fen.push_str(&empty.to_string());
+        }
+
+        if rank > 0 {
+            fen.push('/');
+        }
+    }
+
+    format!(
+        "{} {} {} {}",
+        fen,
+        position.side_to_move,
+        position.half_move_clock,
+        position.ply_count / 2 + 1
+    )
+}
+
+/// square returns the square with the given index, if there is one.
+fn square(index: u8) -> Option<Square> {
+    if index as usize >= Square::N {
+        return None;
+    }
+
+    BitBoard(1 << index).next()
+}
+
+fn invalid(err: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
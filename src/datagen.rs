@@ -0,0 +1,272 @@
+//! datagen implements the generation of training data through self-play. Games
+//! are played between searches with a fixed number of nodes, on multiple
+//! threads, starting from positions with random blocker layouts and a few
+//! random opening moves. Every searched position is written as a [Record] with
+//! its score, visit distribution and the result of its game.
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::{thread, time};
+
+use ataxx::{BitBoard, MoveStore, Piece};
+use rand::Rng;
+
+use crate::data::Record;
+use crate::mcts::{self, value, Policy, Proof, Value};
+
+/// MAX_PLIES is the number of plies after which a game is adjudicated a draw.
+const MAX_PLIES: usize = 400;
+
+/// MAX_BLOCKER_SETS is the maximum number of symmetric sets of blockers put on
+/// the starting board of a game.
+const MAX_BLOCKER_SETS: usize = 4;
+
+/// Range of the number of random moves played at the start of every game,
+/// whose positions aren't recorded.
+const MIN_OPENING_PLIES: usize = 2;
+const MAX_OPENING_PLIES: usize = 8;
+
+/// Config contains the settings of a data generation run.
+pub struct Config {
+    pub games: usize,
+    pub threads: usize,
+    pub nodes: usize,
+}
+
+/// run plays the configured number of games, with searches which use the given
+/// evaluation, and writes the Records of their positions to the given file.
+pub fn run(
+    output: &str,
+    config: &Config,
+    params: &mcts::Params,
+    value: value::Selected,
+) -> Result<(), String> {
+    let file = File::create(output).map_err(|err| format!("{}: {}", output, err))?;
+    let writer = Mutex::new(BufWriter::new(file));
+
+    let started = AtomicUsize::new(0);
+    let finished = AtomicUsize::new(0);
+    let positions = AtomicUsize::new(0);
+
+    let start = time::Instant::now();
+    thread::scope(|scope| {
+        let handles: Vec<_> = (0..config.threads.max(1))
+            .map(|_| {
+                scope.spawn(|| -> Result<(), String> {
+                    let position = ataxx::Position::from_str("x5o/7/7/7/7/7/o5x x 0 1").unwrap();
+                    let mut searcher =
+                        mcts::Searcher::new(position, mcts::policy::Handcrafted, value.clone());
+
+                    searcher.set_params(params.clone());
+                    searcher.set_verbose(false);
+
+                    let mut rng = rand::thread_rng();
+                    while started.fetch_add(1, Ordering::Relaxed) < config.games {
+                        let records = play_game(&mut searcher, &mut rng, config.nodes, params);
+
+                        let mut writer = writer.lock().unwrap();
+                        for record in &records {
+                            record.write(&mut *writer).map_err(|err| err.to_string())?;
+                        }
+                        drop(writer);
+
+                        let games = finished.fetch_add(1, Ordering::Relaxed) + 1;
+                        let total =
+                            positions.fetch_add(records.len(), Ordering::Relaxed) + records.len();
+
+                        if games.is_multiple_of(100) || games == config.games {
+                            let elapsed = start.elapsed().as_secs_f64();
+                            println!(
+                                "games {} positions {} positions/s {:.0}",
+                                games,
+                                total,
+                                total as f64 / elapsed
+                            );
+                        }
+                    }
+
+                    Ok(())
+                })
+            })
+            .collect();
+
+        handles
+            .into_iter()
+            .try_for_each(|handle| handle.join().unwrap())
+    })
+    .map_err(|err| format!("{}: {}", output, err))?;
+
+    writer
+        .into_inner()
+        .unwrap()
+        .flush()
+        .map_err(|err| format!("{}: {}", output, err))
+}
+
+/// play_game plays a game of self-play with the given Searcher, searching the
+/// given number of nodes every move, and returns the Records of its positions.
+fn play_game<P: Policy, V: Value, R: Rng>(
+    searcher: &mut mcts::Searcher<P, V>,
+    rng: &mut R,
+    nodes: usize,
+    params: &mcts::Params,
+) -> Vec<Record> {
+    let mut position = opening(rng);
+    let mut records = vec![];
+
+    let mut total_nodes = 0;
+    let winner = loop {
+        if position.is_game_over() {
+            break position.winner();
+        }
+
+        if records.len() >= MAX_PLIES {
+            break Piece::None;
+        }
+
+        let limits = mcts::Limits {
+            maxdepth: None,
+            maxnodes: Some(nodes),
+            movetime: None,
+            time: None,
+            incr: None,
+            movestogo: None,
+            ponder: false,
+        };
+
+        searcher.update_position(position);
+        let mov = searcher.search(limits, &mut total_nodes);
+
+        let line = &searcher.lines(1)[0];
+        let score = match line.proof.score() {
+            Some(score) => score,
+            None => line.score,
+        };
+
+        records.push(Record {
+            position,
+            // Scores of decided positions saturate at the bounds of an i16.
+            score: mcts::value::wdl_to_eval(score, params.eval_scale()).round() as i16,
+            result: 0.0,
+            visits: visits(searcher.root_visits()),
+        });
+
+        // The result of the game is known once the root has been proven.
+        let stm = position.side_to_move;
+        match line.proof {
+            Proof::Win(_) => break stm,
+            Proof::Loss(_) => break !stm,
+            Proof::Draw => break Piece::None,
+            Proof::Unknown => {}
+        }
+
+        position = position.after_move::<true>(mov);
+    };
+
+    for record in records.iter_mut() {
+        record.result = if winner == Piece::None {
+            0.5
+        } else if winner == record.position.side_to_move {
+            1.0
+        } else {
+            0.0
+        };
+    }
+
+    records
+}
+
+/// opening returns the starting position of a game, which has a random set of
+/// symmetric blockers and a random number of random moves played on it.
+fn opening<R: Rng>(rng: &mut R) -> ataxx::Position {
+    loop {
+        let mut blockers = BitBoard::EMPTY;
+        for _ in 0..rng.gen_range(0..=MAX_BLOCKER_SETS) {
+            let (file, rank) = (rng.gen_range(0..7), rng.gen_range(0..7));
+
+            // A blocker on the centre has no mirrors, and an odd number of
+            // blockers would allow the board to fill up with a tied count of
+            // pieces, which the ataxx crate considers impossible.
+            if (file, rank) == (3, 3) {
+                continue;
+            }
+
+            // Mirror the blocker along both axes, like the blockers of the
+            // standard Ataxx layouts.
+            let mirrors = [
+                (file, rank),
+                (6 - file, rank),
+                (file, 6 - rank),
+                (6 - file, 6 - rank),
+            ];
+            for (file, rank) in mirrors {
+                blockers |= BitBoard(1 << (rank * 7 + file));
+            }
+        }
+
+        let mut position = ataxx::Position::from_str("x5o/7/7/7/7/7/o5x x 0 1").unwrap();
+
+        // The pieces start in the corners, which can't be blocked.
+        let pieces = position.bitboard(Piece::Black) | position.bitboard(Piece::White);
+        position = ataxx::Position::new(
+            position.bitboard(Piece::Black),
+            position.bitboard(Piece::White),
+            blockers & !pieces,
+            Piece::Black,
+            0,
+            0,
+        );
+
+        for _ in 0..rng.gen_range(MIN_OPENING_PLIES..=MAX_OPENING_PLIES) {
+            if position.is_game_over() {
+                break;
+            }
+
+            let moves = position.generate_moves();
+            let mov = moves.at(rng.gen_range(0..moves.len()));
+            position = position.after_move::<true>(mov);
+        }
+
+        if !position.is_game_over() {
+            return position;
+        }
+    }
+}
+
+/// visits converts the given visit distribution into the one stored in the
+/// Records, leaving out unvisited moves and scaling the visits down to fit.
+fn visits(visits: Vec<(ataxx::Move, usize)>) -> Vec<(ataxx::Move, u16)> {
+    let max = visits.iter().map(|&(_, visits)| visits).max().unwrap_or(0);
+    let scale = (max as f64 / u16::MAX as f64).max(1.0);
+
+    visits
+        .into_iter()
+        .filter(|&(_, visits)| visits > 0)
+        .map(|(mov, visits)| (mov, (visits as f64 / scale).round() as u16))
+        .collect()
+}
+
+/// dump writes the Records in the given binary file to the given file in the
+/// text format of labelled positions.
+pub fn dump(input: &str, output: &str) -> Result<usize, String> {
+    let file = File::open(input).map_err(|err| format!("{}: {}", input, err))?;
+    let mut reader = std::io::BufReader::new(file);
+
+    let file = File::create(output).map_err(|err| format!("{}: {}", output, err))?;
+    let mut writer = BufWriter::new(file);
+
+    let mut count = 0;
+    while let Some(record) =
+        Record::read(&mut reader).map_err(|err| format!("{}: {}", input, err))?
+    {
+        writeln!(writer, "{}", record).map_err(|err| format!("{}: {}", output, err))?;
+        count += 1;
+    }
+
+    writer
+        .flush()
+        .map_err(|err| format!("{}: {}", output, err))?;
+    Ok(count)
+}
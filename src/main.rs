@@ -1,113 +1,299 @@
-use std::{env, str::FromStr, time};
+use std::sync::Arc;
+use std::{env, fs, str::FromStr, time};
 
 use uxi::Client;
 
 mod commands;
+mod data;
+mod datagen;
 mod mcts;
 mod options;
+mod texel;
+mod train;
+mod train_policy;
+mod tune;
 
 #[rustfmt::skip]
 fn main() {
-    let args: Vec<String> = env::args().collect();
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let mut params = mcts::Params::new();
+    let mut weights = mcts::value::Weights::default();
+    let mut network = mcts::nnue::Network::embedded();
+    let mut eval_file = options::DEFAULT_EVAL_FILE.to_string();
+    let mut memory = mcts::Memory::default();
+    let mut evaluation = "network".to_string();
 
-    if args.len() > 1 {
-        if args.len() != 2 || args[1] != "bench" {
-            eprintln!("error: unexpected number of command line arguments");
+    // Load the tuned values of the search parameters and of the handcrafted
+    // evaluation's weights, and the value network, from the files given with
+    // their flags, choose how the memory of the search tree is managed, and
+    // choose the evaluation used to generate data.
+    while args.first().is_some_and(|arg| arg.starts_with("--")) {
+        if args.len() < 2 {
+            eprintln!("error: expected a value after {}", args[0]);
             return;
         }
 
-        const BENCH_FENS: &[&str] = &[
-            "x-1-1-o/-1-1-1-/1-1-1-1/-1-1-1-/1-1-1-1/-1-1-1-/o-1-1-x x 0 1",
-            // "x-1-1-o/1-1-1-1/1-1-1-1/1-1-1-1/1-1-1-1/1-1-1-1/o-1-1-x x 0 1",
-            "x1-1-1o/2-1-2/-------/2-1-2/-------/2-1-2/o1-1-1x x 0 1",
-            // "x5o/1-----1/1-3-1/1-1-1-1/1-3-1/1-----1/o5x x 0 1",
-            "x-1-1-o/1-1-1-1/-1-1-1-/-1-1-1-/-1-1-1-/1-1-1-1/o-1-1-x x 0 1",
-            "x5o/1--1--1/1--1--1/7/1--1--1/1--1--1/o5x x 0 1",
-            // "x-3-o/1-1-1-1/1-1-1-1/3-3/1-1-1-1/1-1-1-1/o-3-x x 0 1",
-            // "x2-2o/3-3/3-3/-------/3-3/3-3/o2-2x x 0 1",
-            // "x2-2o/2-1-2/1-3-1/-2-2-/1-3-1/2-1-2/o2-2x x 0 1",
-            "x5o/7/7/7/7/7/o5x x 0 1",
-            "x5o/7/2-1-2/7/2-1-2/7/o5x x 0 1",
-            "x5o/7/3-3/2-1-2/3-3/7/o5x x 0 1",
-            "x2-2o/3-3/2---2/7/2---2/3-3/o2-2x x 0 1",
-            "x2-2o/3-3/7/--3--/7/3-3/o2-2x x 0 1",
-            "x1-1-1o/2-1-2/2-1-2/7/2-1-2/2-1-2/o1-1-1x x 0 1",
-            // "x5o/7/2-1-2/3-3/2-1-2/7/o5x x 0 1",
-            // "x5o/7/3-3/2---2/3-3/7/o5x x 0 1",
-            "x5o/2-1-2/1-3-1/7/1-3-1/2-1-2/o5x x 0 1",
-            "x5o/1-3-1/2-1-2/7/2-1-2/1-3-1/o5x x 0 1",
-            "2x3o/7/7/7/o6/5x1/6x o 2 2",
-            "5oo/7/x6/x6/7/7/o5x o 0 2",
-            "x5o/1x5/7/7/7/2o4/4x2 o 0 2",
-            "7/7/2x1o2/1x5/7/7/o5x o 0 2",
-            "7/7/1x4o/7/4x2/7/o6 o 3 2",
-            "x5o/7/6x/7/1o5/7/7 o 3 2",
-            "5oo/7/2x4/7/7/4x2/o6 o 1 2",
-            "x5o/7/7/3x3/7/1o5/o6 o 1 2",
-            "x5o/7/7/7/7/2x1x2/3x3 o 0 2",
-            "7/7/1x4o/7/7/4x2/o6 o 3 2",
-            "x5o/7/7/5x1/5x1/1o5/o6 o 0 2",
-            "6o/7/4x2/7/7/1o5/o5x o 1 2",
-            "x5o/x5o/7/7/7/6x/o5x o 0 2",
-            "4x1o/7/7/7/7/o6/o5x o 1 2",
-            "6o/7/x6/7/7/2o4/6x o 3 2",
-            "x5o/7/7/7/1o4x/7/5x1 o 2 2",
-            "x5o/6o/7/7/4x2/7/o6 o 1 2",
-            "7/7/1xx1o2/7/7/7/o5x o 0 2",
-            "2x3o/2x4/7/7/7/7/2o3x o 0 2",
-            "x5o/6o/7/7/4x2/3x3/o6 o 0 2",
-            "x5o/7/7/7/o3xx1/7/7 o 0 2",
-            "6o/6o/1x5/7/4x2/7/o6 o 1 2",
-            "7/7/4x1o/7/7/7/o5x o 3 2",
-            "4o2/7/2x4/7/7/7/o4xx o 0 2",
-            "2x3o/x6/7/7/7/o6/o5x o 1 2",
-            "6o/7/2x4/7/1o5/7/4x2 o 3 2",
-            "x6/4o2/7/7/6x/7/o6 o 3 2",
-            "x6/7/5o1/7/7/4x2/o6 o 3 2",
-            "x5o/1x4o/7/7/7/7/o3x2 o 0 2",
-            "xx4o/7/7/7/7/6x/oo4x o 0 2",
-            "x6/7/4x2/3x3/7/7/o5x o 2 2",
-        ];
-
-        let mut total_nodes = 0;
-
-        let start = time::Instant::now();
-        for (i, fen) in BENCH_FENS.iter().enumerate() {
-            println!("[#{}] {}", i + 1, fen);
-            let position = ataxx::Position::from_str(fen).unwrap();
-            let mut searcher = mcts::Searcher::new(position, mcts::policy::handcrafted, mcts::value::material);
-            let limits = mcts::Limits {
-                maxnodes: Some(50000),
-                maxdepth: Some(10),
-                movetime: None,
-                movestogo: None,
-            };
+        let loaded = match args[0].as_str() {
+            "--params"  => tune::load(&mut params, &args[1]),
+            "--weights" => mcts::value::Weights::load(&args[1]).map(|loaded| weights = loaded),
+            "--network" => mcts::nnue::Network::load(&args[1]).map(|loaded| {
+                network = loaded;
+                eval_file = args[1].clone();
+            }),
+            "--memory"  => args[1].parse().map(|parsed| memory = parsed),
+            "--evaluation" => match args[1].as_str() {
+                "network" | "handcrafted" | "material" => {
+                    evaluation = args[1].clone();
+                    Ok(())
+                }
+                unknown => Err(format!("unknown evaluation {}", unknown)),
+            },
+            flag => Err(format!("unknown flag {}", flag)),
+        };
 
-            searcher.search(limits, &mut total_nodes);
+        if let Err(err) = loaded {
+            eprintln!("error: {}", err);
+            return;
         }
-        let elapsed = start.elapsed().as_millis();
 
-        // Assert that the node-count hasn't changed unexpectedly.
-        debug_assert!(total_nodes == 2152064);
+        args.drain(..2);
+    }
 
-        println!("nodes {} nps {}", total_nodes, total_nodes as u128 * 1000 / elapsed);
+    match args.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+        [] => {}
+        ["bench"] => return bench(&params, &network, memory),
+        ["tune"] => return print!("{}", tune::spsa_input(&params)),
+        ["weights"] => return print!("{}", weights),
+        ["tune-eval", data] => return tune_eval(data, "weights.txt", weights, &params),
+        ["tune-eval", data, output] => return tune_eval(data, output, weights, &params),
+        ["train", data] => return train(data, "value.nnue", &params),
+        ["train", data, output] => return train(data, output, &params),
+        ["train-policy", data] => return train_policy(data, "policy.net"),
+        ["train-policy", data, output] => return train_policy(data, output),
+        ["datagen", output, ref config @ ..] => {
+            let value = match evaluation.as_str() {
+                "handcrafted" => mcts::value::Selected::Handcrafted(mcts::value::Handcrafted { weights }),
+                "material" => mcts::value::Selected::Material(mcts::value::Material),
+                _ => mcts::value::Selected::Network(Arc::new(network)),
+            };
 
-        return
+            return datagen(output, config, &params, value);
+        }
+        ["dump", input, output] => return dump(input, output),
+        _ => {
+            eprintln!("error: unexpected command line arguments");
+            return;
+        }
     }
 
-    Client::new()
+    let mut client = Client::new()
         .protocol("uai")
         .engine("Mexx v0.0.0")
         .author("Rak Laptudirm")
         // Register engine options.
-        .option("Hash",    options::hash   ())
-        .option("Threads", options::threads())
+        .option("Hash",         options::hash         ())
+        .option("Ponder",       options::ponder       ())
+        .option("Threads",      options::threads      ())
+        .option("MoveOverhead", options::move_overhead())
+        .option("MultiPV",      options::multipv      ())
+        .option("Evaluation",   options::evaluation   ())
+        .option("EvalFile",     options::eval_file    (&eval_file))
+        .option("PolicyFile",   options::policy_file  ())
+        .option("PolicyNet",    options::policy_net   ())
+        .option("Simulation",   options::simulation   ())
+        .option("PlayoutMoves", options::playout_moves())
         // Register the custom commands.
         .command(         "d", commands::d())
         .command(        "go", commands::go())
         .command(  "protocol", commands::protocol())
         .command(  "position", commands::position())
+        .command(      "stop", commands::stop())
+        .command( "ponderhit", commands::ponderhit())
         .command("uainewgame", commands::uainewgame())
-        // Start the Client so it can start running Commands.
-        .start(Default::default());
+        .command(      "tune", commands::tune())
+        .command(      "eval", commands::eval());
+
+    // Register the search parameters as options, so they can be tuned.
+    for param in params.iter() {
+        client = client.option(param.name(), options::param(param));
+    }
+
+    // The network given at startup is the default value of EvalFile.
+    let mut context = commands::Context::new(eval_file, Arc::new(network));
+    context.weights = weights;
+    context.searcher.as_mut().unwrap().set_memory(memory);
+
+    // Start the Client so it can start running Commands.
+    client.start(context);
+}
+
+#[rustfmt::skip]
+fn bench(params: &mcts::Params, network: &mcts::nnue::Network, memory: mcts::Memory) {
+    const BENCH_FENS: &[&str] = &[
+        "x-1-1-o/-1-1-1-/1-1-1-1/-1-1-1-/1-1-1-1/-1-1-1-/o-1-1-x x 0 1",
+        // "x-1-1-o/1-1-1-1/1-1-1-1/1-1-1-1/1-1-1-1/1-1-1-1/o-1-1-x x 0 1",
+        "x1-1-1o/2-1-2/-------/2-1-2/-------/2-1-2/o1-1-1x x 0 1",
+        // "x5o/1-----1/1-3-1/1-1-1-1/1-3-1/1-----1/o5x x 0 1",
+        "x-1-1-o/1-1-1-1/-1-1-1-/-1-1-1-/-1-1-1-/1-1-1-1/o-1-1-x x 0 1",
+        "x5o/1--1--1/1--1--1/7/1--1--1/1--1--1/o5x x 0 1",
+        // "x-3-o/1-1-1-1/1-1-1-1/3-3/1-1-1-1/1-1-1-1/o-3-x x 0 1",
+        // "x2-2o/3-3/3-3/-------/3-3/3-3/o2-2x x 0 1",
+        // "x2-2o/2-1-2/1-3-1/-2-2-/1-3-1/2-1-2/o2-2x x 0 1",
+        "x5o/7/7/7/7/7/o5x x 0 1",
+        "x5o/7/2-1-2/7/2-1-2/7/o5x x 0 1",
+        "x5o/7/3-3/2-1-2/3-3/7/o5x x 0 1",
+        "x2-2o/3-3/2---2/7/2---2/3-3/o2-2x x 0 1",
+        "x2-2o/3-3/7/--3--/7/3-3/o2-2x x 0 1",
+        "x1-1-1o/2-1-2/2-1-2/7/2-1-2/2-1-2/o1-1-1x x 0 1",
+        // "x5o/7/2-1-2/3-3/2-1-2/7/o5x x 0 1",
+        // "x5o/7/3-3/2---2/3-3/7/o5x x 0 1",
+        "x5o/2-1-2/1-3-1/7/1-3-1/2-1-2/o5x x 0 1",
+        "x5o/1-3-1/2-1-2/7/2-1-2/1-3-1/o5x x 0 1",
+        "2x3o/7/7/7/o6/5x1/6x o 2 2",
+        "5oo/7/x6/x6/7/7/o5x o 0 2",
+        "x5o/1x5/7/7/7/2o4/4x2 o 0 2",
+        "7/7/2x1o2/1x5/7/7/o5x o 0 2",
+        "7/7/1x4o/7/4x2/7/o6 o 3 2",
+        "x5o/7/6x/7/1o5/7/7 o 3 2",
+        "5oo/7/2x4/7/7/4x2/o6 o 1 2",
+        "x5o/7/7/3x3/7/1o5/o6 o 1 2",
+        "x5o/7/7/7/7/2x1x2/3x3 o 0 2",
+        "7/7/1x4o/7/7/4x2/o6 o 3 2",
+        "x5o/7/7/5x1/5x1/1o5/o6 o 0 2",
+        "6o/7/4x2/7/7/1o5/o5x o 1 2",
+        "x5o/x5o/7/7/7/6x/o5x o 0 2",
+        "4x1o/7/7/7/7/o6/o5x o 1 2",
+        "6o/7/x6/7/7/2o4/6x o 3 2",
+        "x5o/7/7/7/1o4x/7/5x1 o 2 2",
+        "x5o/6o/7/7/4x2/7/o6 o 1 2",
+        "7/7/1xx1o2/7/7/7/o5x o 0 2",
+        "2x3o/2x4/7/7/7/7/2o3x o 0 2",
+        "x5o/6o/7/7/4x2/3x3/o6 o 0 2",
+        "x5o/7/7/7/o3xx1/7/7 o 0 2",
+        "6o/6o/1x5/7/4x2/7/o6 o 1 2",
+        "7/7/4x1o/7/7/7/o5x o 3 2",
+        "4o2/7/2x4/7/7/7/o4xx o 0 2",
+        "2x3o/x6/7/7/7/o6/o5x o 1 2",
+        "6o/7/2x4/7/1o5/7/4x2 o 3 2",
+        "x6/4o2/7/7/6x/7/o6 o 3 2",
+        "x6/7/5o1/7/7/4x2/o6 o 3 2",
+        "x5o/1x4o/7/7/7/7/o3x2 o 0 2",
+        "xx4o/7/7/7/7/6x/oo4x o 0 2",
+        "x6/7/4x2/3x3/7/7/o5x o 2 2",
+    ];
+
+    let network = Arc::new(network.clone());
+
+    let mut total_nodes = 0;
+    let mut total_hits = 0;
+
+    let start = time::Instant::now();
+    for (i, fen) in BENCH_FENS.iter().enumerate() {
+        println!("[#{}] {}", i + 1, fen);
+        let position = ataxx::Position::from_str(fen).unwrap();
+        let mut searcher = mcts::Searcher::new(position, mcts::policy::Handcrafted, network.clone());
+        let limits = mcts::Limits {
+            maxnodes: Some(50000),
+            maxdepth: Some(10),
+            movetime: None,
+            time: None,
+            incr: None,
+            movestogo: None,
+            ponder: false,
+        };
+
+        searcher.set_params(params.clone());
+        searcher.set_memory(memory);
+        searcher.update_position(position);
+        searcher.search(limits, &mut total_nodes);
+        total_hits += searcher.table_hits();
+    }
+    let elapsed = start.elapsed().as_millis();
+
+    // Assert that the node-count hasn't changed unexpectedly.
+    debug_assert!(total_nodes == 1906048);
+
+    // Report the number of evaluations saved by the transposition table.
+    println!("table hits {} ({:.2}% of nodes)", total_hits, total_hits as f64 * 100.0 / total_nodes as f64);
+    println!("nodes {} nps {}", total_nodes, total_nodes as u128 * 1000 / elapsed);
+}
+
+/// tune_eval tunes the handcrafted evaluation's weights on the labelled
+/// positions in the given data file and writes them to the given file. The
+/// eval_scale search parameter is tuned along with them, and is printed in the
+/// format of the --params file.
+fn tune_eval(data: &str, output: &str, weights: mcts::value::Weights, params: &mcts::Params) {
+    let (weights, scale) = match texel::tune(data, weights, params.eval_scale()) {
+        Ok(tuned) => tuned,
+        Err(err) => return eprintln!("error: {}", err),
+    };
+
+    if let Err(err) = fs::write(output, weights.to_string()) {
+        return eprintln!("error: {}: {}", output, err);
+    }
+
+    println!("wrote the tuned weights to {}", output);
+    print!("{}", weights);
+
+    // The scale is a search parameter, so print it in the format of --params.
+    println!("eval_scale, {}", options::param_spin(scale));
+}
+
+/// train trains a value network on the labelled positions in the given data
+/// file and writes it to the given file, from where it can be loaded with the
+/// --network flag or the EvalFile option.
+fn train(data: &str, output: &str, params: &mcts::Params) {
+    match train::train(data, output, params.eval_scale()) {
+        Ok(()) => println!("wrote the trained network to {}", output),
+        Err(err) => eprintln!("error: {}", err),
+    }
+}
+
+/// train_policy trains a policy network on the visit distributions of the
+/// positions in the given data file and writes it to the given file, from
+/// where it can be loaded with the PolicyFile option.
+fn train_policy(data: &str, output: &str) {
+    match train_policy::train(data, output) {
+        Ok(()) => println!("wrote the trained network to {}", output),
+        Err(err) => eprintln!("error: {}", err),
+    }
+}
+
+/// datagen generates training data through self-play and writes it to the
+/// given file. The number of games, threads and nodes searched every move can
+/// be given in that order, and default to 1000 games on 1 thread at 5000 nodes.
+/// The games are played with the given evaluation.
+fn datagen(output: &str, config: &[&str], params: &mcts::Params, value: mcts::value::Selected) {
+    let mut values = [1000, 1, 5000];
+    if config.len() > values.len() {
+        return eprintln!("error: expected at most 3 datagen arguments");
+    }
+
+    for (value, arg) in values.iter_mut().zip(config) {
+        match arg.parse() {
+            Ok(parsed) if parsed > 0 => *value = parsed,
+            _ => return eprintln!("error: bad datagen argument {:?}", arg),
+        }
+    }
+
+    let [games, threads, nodes] = values;
+    let config = datagen::Config {
+        games,
+        threads,
+        nodes,
+    };
+
+    match datagen::run(output, &config, params, value) {
+        Ok(()) => println!("wrote {} games to {}", games, output),
+        Err(err) => eprintln!("error: {}", err),
+    }
+}
+
+/// dump writes the self-play data in the given binary file to the given file
+/// in the text format of labelled positions.
+fn dump(input: &str, output: &str) {
+    match datagen::dump(input, output) {
+        Ok(count) => println!("wrote {} positions to {}", count, output),
+        Err(err) => eprintln!("error: {}", err),
+    }
 }
@@ -1,27 +1,66 @@
-use std::time;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock, RwLockReadGuard};
+use std::{thread, time};
+
+use ataxx::MoveStore;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 
 pub use self::params::*;
+pub use self::policy::Policy;
 pub use self::tree::*;
+pub use self::value::Value;
 
+pub mod nnue;
 pub mod policy;
+pub mod policy_net;
 pub mod value;
 
 mod params;
+mod table;
+mod timeman;
 mod tree;
 
-#[derive(Clone)]
-pub struct Searcher {
-    tree: Tree,
+/// TABLE_KIB_PER_MIB is the number of kibibytes out of every mebibyte of the
+/// hash size which are used by the Table. The rest is used by the search Tree.
+const TABLE_KIB_PER_MIB: usize = 64;
+
+/// tree_kib returns the number of kibibytes of memory used by the search Tree
+/// for the given hash size in mebibytes, after the Table's share is taken out.
+fn tree_kib(hash: usize) -> usize {
+    hash * (1024 - TABLE_KIB_PER_MIB)
+}
+
+/// VERIFY_INTERVAL is the number of rollouts of the main search thread after
+/// which the Tree is verified, with the debug-verify feature enabled.
+#[cfg(feature = "debug-verify")]
+const VERIFY_INTERVAL: usize = 4096;
+
+/// Searcher searches positions with Monte Carlo tree search, using the given
+/// [Policy] for the priors of moves and the given [Value] for evaluating the
+/// positions at the leaves.
+pub struct Searcher<P: Policy, V: Value> {
+    tree: RwLock<Tree>,
+    table: table::Table,
     params: Params,
-    policy: policy::Fn,
-    value: value::Fn,
+    policy: P,
+    value: V,
+
+    root_state: V::State, // Value State of the current search's root position.
+
+    simulation: Simulation,
+
+    threads: usize,
+    hash: usize,
+    memory: Memory,
+
+    move_overhead: u128,
+    multipv: usize,
+    verbose: bool, // Whether the search progress is reported.
 
-    start: time::Instant,
+    handle: Handle,
 
-    rollouts: usize,
-    avgdepth: usize,
-    seldepth: usize,
-    cumdepth: usize,
+    hits: usize, // Number of table hits in the last search.
 }
 
 #[derive(Debug)]
@@ -30,184 +69,745 @@ pub struct Limits {
     pub maxnodes: Option<usize>,
     pub movetime: Option<u128>,
 
-    #[allow(unused)]
+    // Standard time control limits: remaining time and increment of the side
+    // to move, and the number of moves to the next time control.
+    pub time: Option<u128>,
+    pub incr: Option<u128>,
     pub movestogo: Option<usize>,
+
+    // The search is pondering, so the limits are only applied after a
+    // ponderhit is received. The time limit starts at the ponderhit.
+    pub ponder: bool,
+}
+
+/// Simulation is the strategy with which the leaves of the search are scored.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Simulation {
+    /// The leaf is evaluated with the [Value].
+    Evaluate,
+    /// A game is played out from the leaf with uniformly random moves.
+    Random,
+    /// A game is played out from the leaf with moves sampled from the priors
+    /// given by the [Policy].
+    Policy,
+    /// The given number of random moves are played from the leaf, and the
+    /// position reached is evaluated with the [Value].
+    Truncated(usize),
+}
+
+/// Handle allows other threads to control the search of a [Searcher]. The
+/// signals sent through it aren't cleared by the search, so a stop sent right
+/// after a search is requested is received even if the search hasn't started
+/// yet. They should instead be cleared with [Handle::clear] when the search is
+/// requested, so that a signal sent while the Searcher is idle is ignored.
+#[derive(Clone, Default)]
+pub struct Handle {
+    stop: Arc<AtomicBool>,
+    ponderhit: Arc<AtomicBool>,
+}
+
+impl Handle {
+    /// stop makes the search end as soon as possible.
+    pub fn stop(&self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+
+    /// ponderhit makes a pondering search switch to its normal limits.
+    pub fn ponderhit(&self) {
+        self.ponderhit.store(true, Ordering::Relaxed);
+    }
+
+    /// clear withdraws the signals sent till now.
+    pub fn clear(&self) {
+        self.stop.store(false, Ordering::Relaxed);
+        self.ponderhit.store(false, Ordering::Relaxed);
+    }
+}
+
+/// Stats contains the statistics of an ongoing search, which are shared and
+/// updated by all of the threads searching the Tree.
+#[derive(Default)]
+struct Stats {
+    stop: AtomicBool,
+
+    rollouts: AtomicUsize,
+    seldepth: AtomicUsize,
+    cumdepth: AtomicUsize,
+
+    hits: AtomicUsize, // Leaves whose value was found in the Table.
+
+    // Rollouts which have started, but haven't been backed up yet.
+    inflight: AtomicUsize,
 }
 
-impl Searcher {
-    pub fn new(position: ataxx::Position, policy: policy::Fn, value: value::Fn) -> Searcher {
+/// Path contains the edges traversed by a rollout, starting with the root
+/// edge, the hashes of the positions reached by them, and the nodes reached
+/// by them, which are pinned during the rollout, along with the edges of an
+/// expanded leaf, the thread's [Policy] and [Value] States and the random
+/// number generator used by its playouts. It is reused across rollouts to
+/// avoid allocating in the search loop.
+struct Path<P: Policy, V: Value> {
+    edges: Vec<(NodePtr, EdgePtr)>,
+    hashes: Vec<ataxx::Hash>,
+    nodes: Vec<NodePtr>,
+
+    fresh: Edges,
+
+    policy: P::State,
+    value: V::State, // Value State of the current position.
+
+    rng: StdRng,
+}
+
+impl<P: Policy, V: Value> Default for Path<P, V> {
+    fn default() -> Self {
+        Path {
+            edges: vec![],
+            hashes: vec![],
+            nodes: vec![],
+
+            fresh: Edges::new(),
+
+            policy: Default::default(),
+            value: Default::default(),
+
+            rng: StdRng::from_entropy(),
+        }
+    }
+}
+
+/// Leaf represents the way in which the selection phase of a rollout ended.
+enum Leaf {
+    /// The last node needs to be simulated as it is terminal or unvisited.
+    Simulate,
+    /// The last node was unexpanded, so fresh edges were generated for it and
+    /// the given edge was selected from them.
+    Expand(EdgePtr),
+    /// The edge selected from the last node doesn't have a child node yet.
+    Push,
+}
+
+impl<P: Policy, V: Value> Searcher<P, V> {
+    pub fn new(position: ataxx::Position, policy: P, value: V) -> Searcher<P, V> {
         Searcher {
-            tree: Tree::new(position),
+            tree: RwLock::new(Tree::new(position, Memory::Lru, tree_kib(16))),
+            table: table::Table::new_kib(16 * TABLE_KIB_PER_MIB),
             params: Params::new(),
             policy,
             value,
 
-            start: time::Instant::now(),
+            root_state: Default::default(),
+
+            simulation: Simulation::Evaluate,
+
+            threads: 1,
+            hash: 16,
+            memory: Memory::Lru,
+
+            move_overhead: 0,
+            multipv: 1,
+            verbose: true,
 
-            rollouts: 0,
-            avgdepth: 0,
-            seldepth: 0,
-            cumdepth: 0,
+            handle: Default::default(),
+
+            hits: 0,
         }
     }
 
+    /// handle returns a [Handle] which can be used to control the searches
+    /// of this Searcher from other threads.
+    pub fn handle(&self) -> Handle {
+        self.handle.clone()
+    }
+
     pub fn update_position(&mut self, position: ataxx::Position) {
-        self.tree = Tree::new(position);
+        let tree = self.tree.get_mut().unwrap();
+
+        // Reallocate the tree if the hash size or the memory strategy was
+        // changed since it was made.
+        if tree.kib() != tree_kib(self.hash) || tree.memory() != self.memory {
+            tree.resize(self.memory, tree_kib(self.hash));
+            self.table.resize_kib(self.hash * TABLE_KIB_PER_MIB);
+        }
+
+        // Position hashes don't include the blockers, so the stored values are
+        // invalid for positions with a different blocker configuration.
+        let blockers = tree.root_position().bitboard(ataxx::Piece::Block);
+        if blockers != position.bitboard(ataxx::Piece::Block) {
+            self.table.clear();
+        }
+
+        // Try to reuse the previous search tree.
+        tree.reroot(position);
+    }
+
+    /// set_threads sets the number of threads which will be used to search
+    /// the shared Tree during the subsequent searches.
+    pub fn set_threads(&mut self, threads: usize) {
+        self.threads = threads.max(1);
+    }
+
+    /// set_move_overhead sets the number of milliseconds which should be kept
+    /// aside for communication overhead every move during a timed search.
+    pub fn set_move_overhead(&mut self, overhead: u128) {
+        self.move_overhead = overhead;
+    }
+
+    /// set_value sets the [Value] used to evaluate positions during the
+    /// subsequent searches. The Table is cleared if the Value is changed.
+    pub fn set_value(&mut self, value: V)
+    where
+        V: PartialEq,
+    {
+        // The values in the Table were found with the old Value.
+        if value != self.value {
+            self.table.clear();
+        }
+
+        self.value = value;
+    }
+
+    /// set_simulation sets the strategy with which the leaves are scored during
+    /// the subsequent searches. The Table is cleared if the strategy changes.
+    pub fn set_simulation(&mut self, simulation: Simulation) {
+        if simulation != self.simulation {
+            self.table.clear();
+        }
+
+        self.simulation = simulation;
+    }
+
+    /// set_policy sets the [Policy] used to score the moves during the
+    /// subsequent searches.
+    pub fn set_policy(&mut self, policy: P) {
+        self.policy = policy;
+    }
+
+    /// set_params sets the values of the search parameters used during the
+    /// subsequent searches. The Table is cleared if any of them change.
+    pub fn set_params(&mut self, params: Params) {
+        if params != self.params {
+            self.table.clear();
+        }
+
+        self.params = params;
+    }
+
+    /// clear_table removes all the values stored in the Table by the previous
+    /// searches, so that they don't carry over into a new game.
+    pub fn clear_table(&mut self) {
+        self.table.clear();
+    }
+
+    /// set_multipv sets the number of best root moves whose principal
+    /// variations are reported during the subsequent searches.
+    pub fn set_multipv(&mut self, multipv: usize) {
+        self.multipv = multipv.max(1);
+    }
+
+    /// set_verbose sets whether the subsequent searches report their progress
+    /// with info lines.
+    pub fn set_verbose(&mut self, verbose: bool) {
+        self.verbose = verbose;
+    }
+
+    /// set_hash sets the number of mebibytes of memory the search Tree and the
+    /// Table may use together. They are only reallocated when the position is
+    /// next updated.
+    pub fn set_hash(&mut self, mib: usize) {
+        self.hash = mib;
+    }
+
+    /// set_memory sets the strategy with which the search Tree manages its
+    /// memory. The Tree is only reallocated when the position is next updated.
+    pub fn set_memory(&mut self, memory: Memory) {
+        self.memory = memory;
     }
 
     pub fn search(&mut self, limits: Limits, total_nodes: &mut u64) -> ataxx::Move {
+        let stats = Stats::default();
+        let start = time::Instant::now();
+
+        // The value States of the rollouts are updated from the root's one.
+        self.root_state = self
+            .value
+            .state(&self.tree.get_mut().unwrap().root_position());
+
+        // The helper threads search the tree till the main thread stops them,
+        // while the main thread also checks the limits and reports progress.
+        thread::scope(|scope| {
+            for _ in 1..self.threads {
+                scope.spawn(|| {
+                    let mut path = Path::default();
+                    while !stats.stop.load(Ordering::Relaxed) {
+                        self.do_one_rollout(&stats, &mut path);
+                    }
+                });
+            }
+
+            self.main_loop(&limits, &stats, start);
+        });
+
+        *total_nodes += stats.rollouts.load(Ordering::Relaxed) as u64;
+        self.hits = stats.hits.load(Ordering::Relaxed);
+
+        let tree = self.tree.get_mut().unwrap();
+
+        // Verify the tree.
+        debug_assert_eq!(tree.verify(), Ok(()));
+
+        tree.best_move()
+    }
+
+    /// table_hits returns the number of leaves of the last search whose value
+    /// was found in the transposition table, and so didn't need evaluating.
+    pub fn table_hits(&self) -> usize {
+        self.hits
+    }
+
+    /// lines returns the given number of best moves found by the last search,
+    /// each along with its principal variation and statistics.
+    pub fn lines(&mut self, count: usize) -> Vec<Line> {
+        self.tree.get_mut().unwrap().lines(count)
+    }
+
+    /// root_visits returns the visit distribution of the moves at the root
+    /// after the last search.
+    pub fn root_visits(&mut self) -> Vec<(ataxx::Move, usize)> {
+        let tree = self.tree.get_mut().unwrap();
+        tree.edges(tree.root())
+            .iter()
+            .map(|edge| (edge.mov, edge.visits()))
+            .collect()
+    }
+
+    /// ponder_move returns the expected reply to the best move found by the
+    /// last search, if there is one.
+    pub fn ponder_move(&mut self) -> Option<ataxx::Move> {
+        self.tree.get_mut().unwrap().ponder_move()
+    }
+
+    fn main_loop(&self, limits: &Limits, stats: &Stats, start: time::Instant) {
         let maxdepth = limits.maxdepth.unwrap_or(usize::MAX);
         let maxnodes = limits.maxnodes.unwrap_or(usize::MAX);
-        let movetime = limits.movetime.unwrap_or(u128::MAX);
 
-        self.start = time::Instant::now();
+        let position = self.tree.read().unwrap().root_position();
+        let mut timeman = timeman::Manager::new(limits, self.move_overhead, &position);
+
+        let mut avgdepth = 0;
+        let mut iterations = 0;
 
-        self.rollouts = 0;
+        // Time of the ponderhit while pondering, otherwise the search start.
+        let mut clock = start;
+        let mut pondering = limits.ponder;
 
-        self.avgdepth = 0;
-        self.seldepth = 0;
-        self.cumdepth = 0;
+        let mut path = Path::default();
 
         loop {
-            let mut new_depth = 0;
-            let mut position = self.tree.root_position();
+            self.do_one_rollout(stats, &mut path);
+            iterations += 1;
 
-            self.do_one_rollout(0, &mut position, &mut new_depth);
-            self.rollouts += 1;
+            let rollouts = stats.rollouts.load(Ordering::Relaxed);
 
-            self.cumdepth += new_depth;
-            if new_depth > self.seldepth {
-                self.seldepth = new_depth;
+            // The write lock keeps the other threads from changing the Tree
+            // while it is being verified.
+            #[cfg(feature = "debug-verify")]
+            if iterations % VERIFY_INTERVAL == 0 {
+                let in_flight = stats.inflight.load(Ordering::Relaxed);
+                if let Err(err) = self.tree.write().unwrap().verify_in_flight(in_flight) {
+                    panic!("tree broken after {} rollouts: {}", rollouts, err);
+                }
             }
 
-            let avg_depth = self.cumdepth / self.rollouts;
-            if avg_depth > self.avgdepth {
-                self.avgdepth = avg_depth;
+            let avg_depth = stats.cumdepth.load(Ordering::Relaxed) / rollouts;
+            if avg_depth > avgdepth {
+                avgdepth = avg_depth;
 
                 // Make a new info report.
-                self.uci_report();
+                self.uci_report(stats, avgdepth, start);
+            }
+
+            if iterations & 127 != 0 {
+                continue;
             }
 
-            if self.rollouts & 127 == 0
-                && (self.start.elapsed().as_millis() >= movetime
-                    || self.avgdepth >= maxdepth
-                    || self.rollouts >= maxnodes)
-            {
+            if self.handle.stop.load(Ordering::Relaxed) {
                 break;
             }
+
+            // The visit counters of the edges can't go on forever.
+            if self.tree.read().unwrap().edge(-1, -1).visits() >= MAX_VISITS {
+                break;
+            }
+
+            if pondering {
+                if !self.handle.ponderhit.load(Ordering::Relaxed) {
+                    continue;
+                }
+
+                pondering = false;
+                clock = time::Instant::now();
+            }
+
+            if avgdepth >= maxdepth || rollouts >= maxnodes {
+                break;
+            }
+
+            // There is nothing left to search for once the root is proven,
+            // unless the search has to go on till it is stopped.
+            let limited =
+                timeman.is_some() || limits.maxdepth.is_some() || limits.maxnodes.is_some();
+            if limited && self.tree.read().unwrap().edge(-1, -1).proof.is_proven() {
+                break;
+            }
+
+            if let Some(timeman) = &mut timeman {
+                let best = self.tree.read().unwrap().most_visited();
+                if timeman.should_stop(clock.elapsed().as_millis(), best) {
+                    break;
+                }
+            }
         }
 
-        *total_nodes += self.rollouts as u64;
+        stats.stop.store(true, Ordering::Relaxed);
+
+        self.uci_report(stats, avgdepth, start);
+    }
 
-        self.uci_report();
+    fn uci_report(&self, stats: &Stats, avgdepth: usize, start: time::Instant) {
+        if !self.verbose {
+            return;
+        }
 
-        // Verify the self.
-        debug_assert_eq!(self.tree.verify(), Ok(()));
+        let tree = self.tree.read().unwrap();
+        let lines = tree.lines(self.multipv);
+        drop(tree);
 
-        self.tree.best_move()
+        let rollouts = stats.rollouts.load(Ordering::Relaxed);
+        let seldepth = stats.seldepth.load(Ordering::Relaxed);
+        let nps = rollouts * 1000 / start.elapsed().as_millis().max(1) as usize;
+
+        for (k, line) in lines.iter().enumerate() {
+            let pv_str = line
+                .pv
+                .iter()
+                .map(|mov| mov.to_string())
+                .reduce(|acc, ele| format!("{} {}", acc, ele))
+                .unwrap_or("".to_string());
+
+            let score_str = match line.proof {
+                Proof::Win(plies) => format!("mate {}", plies.div_ceil(2)),
+                Proof::Loss(plies) => format!("mate -{}", plies.div_ceil(2)),
+                Proof::Draw => "cp 0".to_string(),
+                Proof::Unknown => format!(
+                    "cp {:.0}",
+                    value::wdl_to_eval(line.score, self.params.eval_scale())
+                ),
+            };
+
+            println!(
+                "info depth {} seldepth {} multipv {} score {} nodes {} nps {} pv {}",
+                avgdepth,
+                seldepth,
+                k + 1,
+                score_str,
+                rollouts,
+                nps,
+                pv_str,
+            );
+        }
     }
+}
 
-    fn uci_report(&self) {
-        let (pv, score) = self.tree.pv(0);
+impl<P: Policy, V: Value> Clone for Searcher<P, V> {
+    fn clone(&self) -> Self {
+        Searcher {
+            tree: RwLock::new(self.tree.read().unwrap().clone()),
+            table: self.table.clone(),
+            params: self.params.clone(),
+            policy: self.policy.clone(),
+            value: self.value.clone(),
 
-        let pv_str = pv
-            .iter()
-            .map(|mov| mov.to_string())
-            .reduce(|acc, ele| format!("{} {}", acc, ele))
-            .unwrap_or("".to_string());
-
-        let score_str = if score >= 1.0 {
-            format!("mate {}", (pv.len() + 1) / 2)
-        } else if score <= 0.0 {
-            format!("mate -{}", pv.len() / 2)
-        } else {
-            format!("cp {:.0}", value::wdl_to_eval(score))
-        };
+            root_state: self.root_state.clone(),
 
-        println!(
-            "info depth {} seldepth {} score {} nodes {} nps {} pv {}",
-            self.avgdepth,
-            self.seldepth,
-            score_str,
-            self.rollouts,
-            self.rollouts * 1000 / self.start.elapsed().as_millis().max(1) as usize,
-            pv_str,
-        );
+            simulation: self.simulation,
+
+            threads: self.threads,
+            hash: self.hash,
+            memory: self.memory,
+
+            move_overhead: self.move_overhead,
+            multipv: self.multipv,
+            verbose: self.verbose,
+
+            // The clone's searches are controlled independently.
+            handle: Default::default(),
+
+            hits: self.hits,
+        }
     }
 }
 
-impl Searcher {
-    fn do_one_rollout(
-        &mut self,
-        node_ptr: NodePtr,
-        position: &mut ataxx::Position,
-        depth: &mut usize,
-    ) -> Score {
-        *depth += 1;
+impl<P: Policy, V: Value> Searcher<P, V> {
+    /// do_one_rollout performs a single rollout on the shared Tree. The tree
+    /// is descended while holding only a read lock, so that multiple threads
+    /// can select in parallel, with the nodes on the path pinned so they can't
+    /// be purged or moved by other threads. The rollout's changes to the
+    /// structure of the tree are then done while holding a write lock, which
+    /// is only taken if there are any, and the result is backed up while
+    /// holding a read lock again.
+    fn do_one_rollout(&self, stats: &Stats, path: &mut Path<P, V>) {
+        let Path {
+            edges: path,
+            hashes,
+            nodes,
+            fresh,
+            policy,
+            value,
+            rng,
+        } = path;
 
-        self.tree.promote(node_ptr);
+        value.clone_from(&self.root_state);
 
-        let node = self.tree.node(node_ptr);
-        let parent_node = node.parent_node;
-        let parent_edge = node.parent_edge;
+        path.clear();
+        hashes.clear();
+        nodes.clear();
 
-        let edge_visits = self.tree.edge(parent_node, parent_edge).visits;
+        let tree = self.start_rollout(stats);
+        let mut position = tree.root_position();
 
-        let node = self.tree.node_mut(node_ptr);
+        path.push((-1, -1));
+        hashes.push(position.checksum);
+        nodes.push(tree.root());
+
+        tree.pin(tree.root());
+        let mut edge_visits = tree.edge(-1, -1).add_virtual_loss();
+
+        let leaf = loop {
+            let node_ptr = *nodes.last().unwrap();
+
+            let node = tree.node(node_ptr);
+            let (parent_node, parent_edge) = *path.last().unwrap();
+            let parent = tree.edge(parent_node, parent_edge);
+
+            // Proven nodes don't need to be searched any further.
+            if position.is_game_over() || edge_visits == 0 || parent.proof.is_proven() {
+                break Leaf::Simulate;
+            }
 
-        let score = if position.is_game_over() || edge_visits == 0 {
-            self.simulate(position)
-        } else {
             if !node.expanded() {
-                node.expand(position, self.policy)
+                // Expand the node into a fresh list of edges as the Tree can't
+                // be modified while holding a read lock.
+                self.expand(fresh, &position, policy);
+
+                let edge_ptr = self.select_edge(fresh, parent);
+                position = self.play(&position, fresh[edge_ptr as usize].mov, value);
+
+                break Leaf::Expand(edge_ptr);
             }
 
-            let edge_ptr = self.select_edge(node_ptr);
-            let edge = self.tree.edge(node_ptr, edge_ptr);
-            *position = position.after_move::<true>(edge.mov);
+            let edges = tree.edges(node_ptr);
+            let edge_ptr = self.select_edge(edges, parent);
+            let edge = &edges[edge_ptr as usize];
+
+            edge_visits = edge.add_virtual_loss();
+            position = self.play(&position, edge.mov, value);
+            path.push((node_ptr, edge_ptr));
+            hashes.push(position.checksum);
 
-            let mut child_ptr = edge.ptr;
-            if child_ptr == -1 {
-                child_ptr = self.tree.push(Node::new(node_ptr, edge_ptr));
-                self.tree.edge_mut(node_ptr, edge_ptr).ptr = child_ptr;
+            if edge.ptr == -1 {
+                // The child of a proven edge might have been purged, but it
+                // doesn't need to be added back to the Tree.
+                if edge.proof.is_proven() {
+                    break Leaf::Simulate;
+                }
+
+                break Leaf::Push;
             }
 
-            self.do_one_rollout(child_ptr, position, depth)
+            tree.pin(edge.ptr);
+            nodes.push(edge.ptr);
+        };
+
+        // The exact score of a proven leaf is known, so it isn't simulated.
+        let (parent_node, parent_edge) = *path.last().unwrap();
+        let proof = tree.edge(parent_node, parent_edge).proof;
+
+        drop(tree);
+
+        // Use the q value of the leaf from the Table if it has been searched
+        // before, through a different move order, instead of evaluating it.
+        // The hashes don't include the half-move clock, so the value of a
+        // finished game, which might be a draw by it, is never taken from or
+        // stored in the Table.
+        let over = position.is_game_over();
+        let stored = if over {
+            None
+        } else {
+            self.table.get(position.checksum)
+        };
+
+        let score = match (proof.score(), stored) {
+            (Some(score), _) => 1.0 - score,
+            (None, Some(score)) => {
+                stats.hits.fetch_add(1, Ordering::Relaxed);
+                score
+            }
+            (None, None) => self.simulate(&position, policy, value, rng),
         };
 
-        let score = 1.0 - score;
+        let node_ptr = *nodes.last().unwrap();
+        let mut score = score;
+        let mut simulated = matches!(leaf, Leaf::Simulate);
+        let mut terminal = over;
 
-        let edge = self.tree.edge_mut(parent_node, parent_edge);
+        // A rollout which doesn't add anything to the tree or prove any edges
+        // has nothing to change in it, and only needs to back up its score.
+        if !simulated || terminal || proof.is_proven() {
+            let mut tree = self.tree.write().unwrap();
+
+            if let Leaf::Expand(edge_ptr) = leaf {
+                // Another thread might have expanded the node in the meantime,
+                // but since move generation is deterministic the edges will be
+                // the same.
+                if tree.node(node_ptr).expanded() || tree.expand(node_ptr, fresh) {
+                    tree.edge(node_ptr, edge_ptr).add_virtual_loss();
+                    path.push((node_ptr, edge_ptr));
+                    hashes.push(position.checksum);
+                } else {
+                    // No space could be made for the edges, so the unexpanded
+                    // node becomes the leaf, with the score from its own
+                    // perspective.
+                    score = 1.0 - score;
+                    simulated = true;
+                    terminal = false;
+                }
+            }
 
-        edge.visits += 1;
-        edge.scores += score;
+            if !simulated {
+                let (parent_node, parent_edge) = *path.last().unwrap();
+
+                // The child might have been created by another thread already.
+                // If no space could be made for the child, the edge is left
+                // unlinked.
+                if tree.edge(parent_node, parent_edge).ptr == -1 {
+                    tree.push(Node::new(parent_node, parent_edge));
+                }
+            }
+
+            if terminal {
+                let (parent_node, parent_edge) = *path.last().unwrap();
+                tree.edge_mut(parent_node, parent_edge).proof = Proof::terminal(&position);
+            }
 
-        self.tree.promote(node_ptr);
+            // Propagate the proofs up the path, stopping at the first edge
+            // whose proof doesn't change the proof of its parent edge.
+            for i in (1..path.len()).rev() {
+                let (parent_node, parent_edge) = path[i];
+                if !tree.edge(parent_node, parent_edge).proof.is_proven() {
+                    break;
+                }
 
-        score
+                let proof = tree.node_proof(parent_node);
+                let (parent_node, parent_edge) = path[i - 1];
+                let edge = tree.edge_mut(parent_node, parent_edge);
+                if !proof.is_proven() || edge.proof == proof {
+                    break;
+                }
+
+                edge.proof = proof;
+            }
+
+            // Promote the nodes from the leaf upwards so that parents are
+            // always used more recently than their children. The path is
+            // updated with the new pointers of the nodes which were moved, but
+            // the pins stay on the old ones, which can't be reused while the
+            // rollout is in flight.
+            for (i, &node_ptr) in nodes.iter().enumerate().rev() {
+                let ptr = tree.promote(node_ptr);
+                if let Some((parent_node, _)) = path.get_mut(i + 1) {
+                    *parent_node = ptr;
+                }
+            }
+        }
+
+        let tree = self.tree.read().unwrap();
+
+        // Backup the score to the edges, flipping its perspective at every ply,
+        // and store the new q values of the reached positions in the Table.
+        for (&(parent_node, parent_edge), &hash) in path.iter().zip(hashes.iter()).rev() {
+            score = 1.0 - score;
+            let edge = tree.edge(parent_node, parent_edge);
+            edge.add_score(score);
+            if !(over && hash == position.checksum) {
+                self.table.set(hash, 1.0 - edge.q());
+            }
+        }
+
+        for &node_ptr in nodes.iter() {
+            tree.unpin(node_ptr);
+        }
+
+        stats.inflight.fetch_sub(1, Ordering::Relaxed);
+        drop(tree);
+
+        let depth = path.len();
+        stats.rollouts.fetch_add(1, Ordering::Relaxed);
+        stats.cumdepth.fetch_add(depth, Ordering::Relaxed);
+        stats.seldepth.fetch_max(depth, Ordering::Relaxed);
+    }
+
+    /// start_rollout takes a read lock on the Tree for a new rollout. If the
+    /// Tree is full, the new rollout waits for the rollouts in flight to be
+    /// backed up, as the Tree can only be flipped while none of its nodes are
+    /// in use, and then flips it.
+    fn start_rollout(&self, stats: &Stats) -> RwLockReadGuard<'_, Tree> {
+        loop {
+            let tree = self.tree.read().unwrap();
+            if !tree.full() {
+                // The Tree is only flipped under a write lock, once no rollouts
+                // are in flight, so it can't be flipped during this one.
+                stats.inflight.fetch_add(1, Ordering::Relaxed);
+                return tree;
+            }
+
+            drop(tree);
+
+            let mut tree = self.tree.write().unwrap();
+            if tree.full() && stats.inflight.load(Ordering::Relaxed) == 0 {
+                tree.flip();
+            } else {
+                drop(tree);
+                thread::yield_now();
+            }
+        }
     }
 
     //                    v-----------------------v exploitation
     //  node-q + policy * cpuct * sqrt(node-visits) / (1 + child-visits = 0) // not expanded
     // child-q + policy * cpuct * sqrt(node-visits) / (1 + child-visits)     // expanded
     // ^-----^ score / visits
-    fn select_edge(&self, ptr: NodePtr) -> EdgePtr {
-        let node = self.tree.node(ptr);
-        let parent = self.tree.edge(node.parent_node, node.parent_edge);
-
+    fn select_edge(&self, edges: &[Edge], parent: &Edge) -> EdgePtr {
         // Node exploitation factor (cpuct * sqrt(parent-playouts))
-        let e = self.params.cpuct() * f64::sqrt(parent.visits.max(1) as f64);
+        let e = self.params.cpuct() * f64::sqrt(parent.visits().max(1) as f64);
 
         let mut best_ptr: EdgePtr = -1;
-        let mut best_uct = 0.0;
+        let mut best_uct = f64::NEG_INFINITY;
 
-        let fpu = 1.0 - parent.q();
+        let fpu = 1.0 - parent.q() - self.params.fpu_reduction();
 
-        for (ptr, edge) in node.edges.iter().enumerate() {
-            // If the edge hasn't been expanded yet, use the parent's q value.
-            let q = if edge.ptr == -1 { fpu } else { edge.q() };
+        for (ptr, edge) in edges.iter().enumerate() {
+            let q = match edge.proof {
+                // Never select moves which are proven to lose.
+                Proof::Loss(_) => continue,
+                Proof::Win(_) => 1.0,
+                Proof::Draw => 0.5,
+                // If the edge hasn't been expanded yet, use the parent's q value.
+                Proof::Unknown if edge.ptr == -1 => fpu,
+                Proof::Unknown => edge.q(),
+            };
 
-            let child_uct = q + edge.policy * e / (edge.visits as f64 + 1.0);
+            let child_uct = q + edge.policy() * e / (edge.visits() as f64 + 1.0);
 
             // Check if we have a better UCT score for this edge.
             if child_uct > best_uct {
@@ -216,10 +816,118 @@ impl Searcher {
             }
         }
 
+        // All the moves are proven losses, so any of them will do.
+        if best_ptr == -1 {
+            return 0;
+        }
+
         best_ptr
     }
 
-    fn simulate(&mut self, position: &ataxx::Position) -> f64 {
+    /// expand generates the given edges of the given position, with the priors
+    /// given by the Policy, using the given Policy State.
+    fn expand(&self, edges: &mut Edges, position: &ataxx::Position, state: &mut P::State) {
+        self.policy.prepare(state, &self.params, position);
+        edges.expand(
+            position,
+            |mov| self.policy.logit(state, &self.params, position, mov),
+            self.params.policy_temperature(),
+        );
+    }
+
+    /// play returns the position reached by making the given move in the given
+    /// position, updating the given Value State along with it.
+    fn play(
+        &self,
+        position: &ataxx::Position,
+        mov: ataxx::Move,
+        state: &mut V::State,
+    ) -> ataxx::Position {
+        let next = position.after_move::<true>(mov);
+        self.value.update(state, position, &next);
+        next
+    }
+
+    /// simulate scores the given leaf position, from the perspective of its
+    /// side to move, with the Searcher's [Simulation].
+    fn simulate(
+        &self,
+        position: &ataxx::Position,
+        policy: &mut P::State,
+        value: &V::State,
+        rng: &mut StdRng,
+    ) -> f64 {
+        let moves = match self.simulation {
+            Simulation::Evaluate => return self.evaluate(position, value),
+            Simulation::Random | Simulation::Policy => usize::MAX,
+            Simulation::Truncated(moves) => moves,
+        };
+
+        let stm = position.side_to_move;
+
+        let mut position = *position;
+        let mut state = value.clone();
+        for _ in 0..moves {
+            if position.is_game_over() {
+                break;
+            }
+
+            let mov = self.playout_move(&position, policy, rng);
+
+            // Playouts to the end of the game are never evaluated, so the
+            // Value State doesn't need to be updated along with them.
+            position = if moves == usize::MAX {
+                position.after_move::<false>(mov)
+            } else {
+                self.play(&position, mov, &mut state)
+            };
+        }
+
+        let score = self.evaluate(&position, &state);
+        if position.side_to_move == stm {
+            score
+        } else {
+            1.0 - score
+        }
+    }
+
+    /// playout_move returns the move made in the given position by a playout,
+    /// which is sampled from the priors given by the Policy for policy-weighted
+    /// playouts, and uniformly otherwise.
+    fn playout_move(
+        &self,
+        position: &ataxx::Position,
+        state: &mut P::State,
+        rng: &mut StdRng,
+    ) -> ataxx::Move {
+        let moves = position.generate_moves();
+        if self.simulation != Simulation::Policy {
+            return moves.at(rng.gen_range(0..moves.len()));
+        }
+
+        // Sample from the softmax of the logits with the Gumbel-max trick, by
+        // picking the move with the largest logit after adding Gumbel noise.
+        self.policy.prepare(state, &self.params, position);
+        let temperature = self.params.policy_temperature();
+
+        let mut best = (f64::NEG_INFINITY, moves.at(0));
+        for mov in moves {
+            let logit = self.policy.logit(state, &self.params, position, mov);
+            let noise = -f64::ln(-f64::ln(rng.gen()));
+
+            let key = logit / temperature + noise;
+            if key > best.0 {
+                best = (key, mov);
+            }
+        }
+
+        best.1
+    }
+
+    /// evaluate returns the win probability of the side to move in the given
+    /// position, from the result of the game if it is over, and otherwise
+    /// from the Value's evaluation with the given State.
+    fn evaluate(&self, position: &ataxx::Position, state: &V::State) -> f64 {
         if position.is_game_over() {
             let winner = position.winner();
             return if winner == ataxx::Piece::None {
@@ -231,6 +939,207 @@ impl Searcher {
             };
         };
 
-        value::eval_to_wdl((self.value)(position))
+        let eval = self.value.evaluate(state, position);
+        value::eval_to_wdl(eval, self.params.eval_scale())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+    use std::sync::mpsc;
+
+    use super::*;
+
+    const FENS: &[&str] = &[
+        "x5o/7/7/7/7/7/o5x x 0 1",
+        "x5o/1--1--1/1--1--1/7/1--1--1/1--1--1/o5x x 0 1",
+        "7/7/7/o6/ooooooo/ooooooo/xxxxxxx w 0 1",
+        "xxxxxxx/-------/7/7/7/-------/ooooooo x 0 1",
+    ];
+
+    /// tiny_searcher returns a Searcher whose Tree only has space for a few
+    /// dozen nodes managed with the given strategy, so that its memory is full
+    /// all the time.
+    fn tiny_searcher(fen: &str, memory: Memory) -> Searcher<policy::Handcrafted, value::Material> {
+        let position = ataxx::Position::from_str(fen).unwrap();
+        let mut searcher = Searcher::new(position, policy::Handcrafted, value::Material);
+
+        *searcher.tree.get_mut().unwrap() = Tree::with_capacity(position, memory, 40, 320);
+        searcher.hash = 0;
+        searcher.memory = memory;
+        searcher.set_verbose(false);
+        searcher
+    }
+
+    /// tiny_tree_rollouts does rollouts on tiny trees managed with the given
+    /// strategy, verifying the tree after each of them.
+    fn tiny_tree_rollouts(memory: Memory) {
+        for fen in FENS {
+            let searcher = tiny_searcher(fen, memory);
+            let stats = Stats::default();
+            let mut path = Path::default();
+
+            for i in 0..5000 {
+                searcher.do_one_rollout(&stats, &mut path);
+
+                let tree = searcher.tree.read().unwrap();
+                assert_eq!(tree.verify(), Ok(()), "{} after {} rollouts", fen, i + 1);
+            }
+        }
+    }
+
+    /// tiny_tree_games plays games with multi-threaded searches on tiny trees
+    /// managed with the given strategy, verifying the tree after each move.
+    fn tiny_tree_games(memory: Memory) {
+        for fen in FENS {
+            let mut searcher = tiny_searcher(fen, memory);
+            searcher.set_threads(4);
+
+            let mut position = ataxx::Position::from_str(fen).unwrap();
+            let mut nodes = 0;
+            for _ in 0..20 {
+                if position.is_game_over() {
+                    break;
+                }
+
+                let limits = Limits {
+                    maxdepth: None,
+                    maxnodes: Some(2000),
+                    movetime: None,
+                    time: None,
+                    incr: None,
+                    movestogo: None,
+                    ponder: false,
+                };
+
+                // The tree is reused between the moves of the game.
+                searcher.update_position(position);
+                let mov = searcher.search(limits, &mut nodes);
+
+                let tree = searcher.tree.get_mut().unwrap();
+                assert_eq!(tree.verify(), Ok(()), "{} after {}", fen, mov);
+
+                position = position.after_move::<true>(mov);
+            }
+        }
+    }
+
+    #[test]
+    fn stop_right_after_go() {
+        let position = ataxx::Position::from_str(FENS[0]).unwrap();
+        let mut searcher = Searcher::new(position, policy::Handcrafted, value::Material);
+        searcher.set_verbose(false);
+
+        // A stop sent while idle is withdrawn when the next search is
+        // requested, and a stop sent right after that reaches the search even
+        // if it is sent before the search starts, like "go infinite" followed
+        // by "stop" would.
+        let handle = searcher.handle();
+        handle.stop();
+        handle.clear();
+        handle.stop();
+
+        let limits = Limits {
+            maxdepth: None,
+            maxnodes: None,
+            movetime: None,
+            time: None,
+            incr: None,
+            movestogo: None,
+            ponder: false,
+        };
+
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let mut nodes = 0;
+            sender.send(searcher.search(limits, &mut nodes)).unwrap();
+        });
+
+        let bestmove = receiver
+            .recv_timeout(time::Duration::from_secs(10))
+            .expect("no bestmove after stop");
+        assert!(position
+            .generate_moves()
+            .into_iter()
+            .any(|mov| mov == bestmove));
+    }
+
+    #[test]
+    fn table_cleared_on_changes() {
+        let position = ataxx::Position::from_str(FENS[0]).unwrap();
+        let mut searcher = Searcher::new(position, policy::Handcrafted, value::Material);
+        let hash = position.checksum;
+
+        // Setting the same parameters and strategy keeps the stored values.
+        searcher.table.set(hash, 0.25);
+        searcher.set_params(Params::new());
+        searcher.set_simulation(Simulation::Evaluate);
+        assert_eq!(searcher.table.get(hash), Some(0.25));
+
+        let mut params = Params::new();
+        params.iter_mut().next().unwrap().set(2.0);
+        searcher.set_params(params);
+        assert_eq!(searcher.table.get(hash), None);
+
+        searcher.table.set(hash, 0.25);
+        searcher.set_simulation(Simulation::Random);
+        assert_eq!(searcher.table.get(hash), None);
+
+        searcher.table.set(hash, 0.25);
+        searcher.clear_table();
+        assert_eq!(searcher.table.get(hash), None);
+    }
+
+    #[test]
+    fn table_skips_finished_games() {
+        let position = ataxx::Position::from_str("x5o/7/7/7/7/7/o5x x 99 50").unwrap();
+        let searcher = Searcher::new(position, policy::Handcrafted, value::Material);
+
+        // Every jump draws by the 50-move rule, but the Table holds the value
+        // of a won game for the positions they reach, with a lower clock.
+        let jumps: Vec<_> = position
+            .generate_moves()
+            .into_iter()
+            .filter(|mov| !mov.is_single())
+            .collect();
+        for &mov in &jumps {
+            searcher
+                .table
+                .set(position.after_move::<true>(mov).checksum, 0.0);
+        }
+
+        let stats = Stats::default();
+        let mut path = Path::default();
+        for _ in 0..500 {
+            searcher.do_one_rollout(&stats, &mut path);
+        }
+
+        let tree = searcher.tree.read().unwrap();
+        for edge in tree.edges(tree.root()) {
+            if jumps.contains(&edge.mov) && edge.visits() > 0 {
+                assert_eq!(edge.q(), 0.5, "{}", edge.mov);
+            }
+        }
+    }
+
+    #[test]
+    fn lru_rollouts() {
+        tiny_tree_rollouts(Memory::Lru);
+    }
+
+    #[test]
+    fn lru_games() {
+        tiny_tree_games(Memory::Lru);
+    }
+
+    #[test]
+    fn halves_rollouts() {
+        tiny_tree_rollouts(Memory::Halves);
+    }
+
+    #[test]
+    fn halves_games() {
+        tiny_tree_games(Memory::Halves);
     }
 }
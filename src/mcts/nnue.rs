@@ -0,0 +1,209 @@
+//! nnue implements an efficiently updatable neural network for evaluating
+//! positions. The network has one input for every colour on every square, a
+//! hidden layer which is computed separately from the perspective of both the
+//! sides, and a single output, which is the evaluation of the position from
+//! the perspective of the side to move. Since a move only changes a few of the
+//! inputs, the hidden layer is kept in an [Accumulator] which is updated with
+//! the changes instead of being recomputed for every position.
+use std::fs;
+
+use ataxx::{Piece, Position, Square};
+
+/// HIDDEN is the size of the hidden layer from the perspective of one side.
+pub const HIDDEN: usize = 32;
+
+/// INPUTS is the number of inputs to the network, one for every colour on
+/// every square, with the colours relative to the perspective.
+pub const INPUTS: usize = 2 * Square::N;
+
+pub const QA: i32 = 255; // Quantisation of the hidden layer.
+pub const QB: i32 = 64; // Quantisation of the output weights.
+pub const SCALE: i32 = 400; // Scale of the output into an evaluation.
+
+/// DEFAULT is the network which is embedded in the binary.
+const DEFAULT: &[u8] = include_bytes!("../../nets/default.nnue");
+
+/// Network contains the quantised weights of a value network. It is stored as
+/// a sequence of little endian i16s: the input weights, in input major order,
+/// the hidden biases, the output weights, for the side to move's perspective
+/// followed by the other side's, and the output bias.
+#[derive(Clone)]
+pub struct Network {
+    input_weights: Vec<[i16; HIDDEN]>,
+    hidden_biases: [i16; HIDDEN],
+    output_weights: [i16; 2 * HIDDEN],
+    output_bias: i16,
+}
+
+/// Accumulator contains the hidden layer of a [Network] for a position, from
+/// the perspective of both the sides, before the activation is applied. The
+/// values are wider than the weights, so that they can't overflow whatever
+/// the weights of the loaded network are.
+#[derive(Clone, Copy)]
+pub struct Accumulator {
+    vals: [[i32; HIDDEN]; 2],
+}
+
+impl Default for Accumulator {
+    fn default() -> Self {
+        Accumulator {
+            vals: [[0; HIDDEN]; 2],
+        }
+    }
+}
+
+impl Network {
+    /// SIZE is the number of bytes in a serialized Network.
+    pub const SIZE: usize = 2 * (INPUTS * HIDDEN + HIDDEN + 2 * HIDDEN + 1);
+
+    /// embedded returns the default Network embedded in the binary.
+    pub fn embedded() -> Network {
+        Network::from_bytes(DEFAULT).unwrap()
+    }
+
+    /// load reads a serialized Network from the given file.
+    pub fn load(path: &str) -> Result<Network, String> {
+        let bytes = fs::read(path).map_err(|err| format!("{}: {}", path, err))?;
+        Network::from_bytes(&bytes).map_err(|err| format!("{}: {}", path, err))
+    }
+
+    /// from_bytes deserializes a Network from the given bytes.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Network, String> {
+        if bytes.len() != Network::SIZE {
+            return Err(format!(
+                "network has {} bytes, expected {}",
+                bytes.len(),
+                Network::SIZE
+            ));
+        }
+
+        let mut vals = bytes
+            .chunks_exact(2)
+            .map(|chunk| i16::from_le_bytes([chunk[0], chunk[1]]));
+
+        let mut network = Network {
+            input_weights: vec![[0; HIDDEN]; INPUTS],
+            hidden_biases: [0; HIDDEN],
+            output_weights: [0; 2 * HIDDEN],
+            output_bias: 0,
+        };
+
+        for weights in network.input_weights.iter_mut() {
+            weights.fill_with(|| vals.next().unwrap());
+        }
+
+        network.hidden_biases.fill_with(|| vals.next().unwrap());
+        network.output_weights.fill_with(|| vals.next().unwrap());
+        network.output_bias = vals.next().unwrap();
+
+        Ok(network)
+    }
+
+    /// accumulate computes the Accumulator of the given position from scratch.
+    pub fn accumulate(&self, position: &Position) -> Accumulator {
+        let mut acc = Accumulator {
+            vals: [self.hidden_biases.map(i32::from); 2],
+        };
+
+        for piece in [Piece::Black, Piece::White] {
+            for sq in position.bitboard(piece) {
+                self.add(&mut acc, piece, sq);
+            }
+        }
+
+        acc
+    }
+
+    /// update updates the given Accumulator of the old position into the one
+    /// of the new position, which is reached by making a move in the old one.
+    pub fn update(&self, acc: &mut Accumulator, old: &Position, new: &Position) {
+        for piece in [Piece::Black, Piece::White] {
+            let old_bb = old.bitboard(piece);
+            let new_bb = new.bitboard(piece);
+
+            for sq in old_bb & !new_bb {
+                self.sub(acc, piece, sq);
+            }
+
+            for sq in new_bb & !old_bb {
+                self.add(acc, piece, sq);
+            }
+        }
+    }
+
+    /// evaluate returns the evaluation, from the perspective of the given side
+    /// to move, of the position with the given Accumulator.
+    pub fn evaluate(&self, acc: &Accumulator, stm: Piece) -> f64 {
+        let (stm_weights, xtm_weights) = self.output_weights.split_at(HIDDEN);
+
+        // The output is computed in i64, which can't overflow for any weights.
+        let mut output = self.output_bias as i64;
+        for (vals, weights) in [
+            (&acc.vals[stm as usize], stm_weights),
+            (&acc.vals[!stm as usize], xtm_weights),
+        ] {
+            for (&val, &weight) in vals.iter().zip(weights) {
+                output += val.clamp(0, QA) as i64 * weight as i64;
+            }
+        }
+
+        (output * SCALE as i64) as f64 / (QA * QB) as f64
+    }
+
+    fn add(&self, acc: &mut Accumulator, piece: Piece, sq: Square) {
+        for perspective in [Piece::Black, Piece::White] {
+            let weights = &self.input_weights[input(perspective, piece, sq)];
+            for (val, &weight) in acc.vals[perspective as usize].iter_mut().zip(weights) {
+                *val += weight as i32;
+            }
+        }
+    }
+
+    fn sub(&self, acc: &mut Accumulator, piece: Piece, sq: Square) {
+        for perspective in [Piece::Black, Piece::White] {
+            let weights = &self.input_weights[input(perspective, piece, sq)];
+            for (val, &weight) in acc.vals[perspective as usize].iter_mut().zip(weights) {
+                *val -= weight as i32;
+            }
+        }
+    }
+}
+
+/// input returns the index of the input for the given piece on the given
+/// square, from the given perspective.
+pub fn input(perspective: Piece, piece: Piece, sq: Square) -> usize {
+    (piece != perspective) as usize * Square::N + sq as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    #[test]
+    fn extreme_weights_dont_overflow() {
+        let position =
+            Position::from_str("xxxxxxx/ooooooo/xxxxxxx/ooooooo/xxxxxxx/ooooooo/xxxxxx1 o 0 1")
+                .unwrap();
+
+        for weight in [i16::MAX, i16::MIN] {
+            let bytes: Vec<u8> = (0..Network::SIZE / 2)
+                .flat_map(|_| weight.to_le_bytes())
+                .collect();
+            let network = Network::from_bytes(&bytes).unwrap();
+
+            // The accumulator is updated through a move which captures the
+            // most pieces, and has to match the one computed from scratch.
+            let mov = ataxx::Move::from_str("g1").unwrap();
+            let after = position.after_move::<true>(mov);
+
+            let mut acc = network.accumulate(&position);
+            network.update(&mut acc, &position, &after);
+            assert_eq!(acc.vals, network.accumulate(&after).vals);
+
+            let eval = network.evaluate(&acc, after.side_to_move);
+            assert!(eval.is_finite());
+        }
+    }
+}
@@ -1,36 +1,92 @@
 use std::f64::consts::SQRT_2;
 
-use derive_new::new;
+/// params declares the tunable search parameters, given as their name, default
+/// value, minimum and maximum. An accessor for each parameter's value is made,
+/// along with iterators over all the parameters.
+macro_rules! params {
+    ($($name:ident: $val:expr, $min:expr, $max:expr;)*) => {
+        #[derive(Clone, PartialEq)]
+        pub struct Params {
+            $($name: Param,)*
+        }
 
-#[derive(Clone)]
-pub struct Params {
-    cpuct: Param,
-}
+        impl Params {
+            pub fn new() -> Params {
+                Params {
+                    $($name: Param::new(stringify!($name), $val, $min, $max),)*
+                }
+            }
+
+            $(
+                pub fn $name(&self) -> f64 {
+                    self.$name.val
+                }
+            )*
 
-impl Params {
-    pub fn new() -> Params {
-        Params {
-            cpuct: Param::new(SQRT_2, 1.0, 10.0),
+            /// iter returns an iterator over all the Params.
+            pub fn iter(&self) -> impl Iterator<Item = &Param> {
+                [$(&self.$name,)*].into_iter()
+            }
+
+            /// iter_mut returns a mutable iterator over all the Params.
+            pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut Param> {
+                [$(&mut self.$name,)*].into_iter()
+            }
         }
-    }
-    pub fn cpuct(&self) -> f64 {
-        self.cpuct.val
-    }
+    };
 }
 
-#[derive(Clone, new)]
+params! {
+    cpuct:              SQRT_2,   1.0,   10.0;
+    fpu_reduction:         0.0,   0.0,    0.5;
+    policy_temperature:    1.0,   0.1,    5.0;
+    eval_scale:          400.0, 100.0, 1000.0;
+
+    // Weights of the handcrafted policy's features.
+    policy_capture:        1.0,   0.0,    4.0;
+    policy_support:        0.4,   0.0,    2.0;
+    policy_single:         0.7,   0.0,    3.0;
+    policy_exposed:        0.4,   0.0,    2.0;
+    policy_hole:           0.5,   0.0,    3.0;
+}
+
+#[derive(Clone, PartialEq)]
 pub struct Param {
-    val: f64,
+    name: &'static str,
 
-    #[allow(unused)]
+    val: f64,
     min: f64,
-    #[allow(unused)]
     max: f64,
 }
 
 impl Param {
-    #[allow(unused)]
+    pub fn new(name: &'static str, val: f64, min: f64, max: f64) -> Param {
+        Param {
+            name,
+            val,
+            min,
+            max,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn val(&self) -> f64 {
+        self.val
+    }
+
+    pub fn min(&self) -> f64 {
+        self.min
+    }
+
+    pub fn max(&self) -> f64 {
+        self.max
+    }
+
+    /// set sets the value of the Param, clamped to its bounds.
     pub fn set(&mut self, val: f64) {
-        self.val = val;
+        self.val = val.clamp(self.min, self.max);
     }
 }
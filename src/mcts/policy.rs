@@ -1,8 +1,81 @@
-use ataxx::{BitBoard, Move};
+use std::sync::Arc;
 
-pub type Fn = fn(position: &ataxx::Position, mov: Move) -> f64;
+use ataxx::{BitBoard, Move, Piece, Position};
 
-pub fn handcrafted(position: &ataxx::Position, mov: Move) -> f64 {
+use super::{policy_net, Params};
+
+/// Policy scores the moves of positions with logits, whose softmax is used as
+/// the moves' priors. Every searching thread keeps its own State, which is
+/// prepared for a position before its moves are scored.
+pub trait Policy: Clone + Send + Sync {
+    type State: Default;
+
+    /// prepare prepares the given State for scoring the moves of the given
+    /// position.
+    fn prepare(&self, _state: &mut Self::State, _params: &Params, _position: &Position) {}
+
+    /// logit returns the logit of the given move in the given position, with
+    /// the State prepared for it.
+    fn logit(&self, state: &Self::State, params: &Params, position: &Position, mov: Move) -> f64;
+}
+
+/// Handcrafted scores moves by the changes they make around their source and
+/// target squares, weighted by the policy's search parameters.
+#[derive(Clone, Copy)]
+pub struct Handcrafted;
+
+impl Policy for Handcrafted {
+    type State = ();
+
+    fn logit(&self, _state: &(), params: &Params, position: &Position, mov: Move) -> f64 {
+        handcrafted(params, position, mov)
+    }
+}
+
+/// A policy network's State is the hidden layer of the position.
+impl Policy for Arc<policy_net::Network> {
+    type State = policy_net::Hidden;
+
+    fn prepare(&self, hidden: &mut policy_net::Hidden, _params: &Params, position: &Position) {
+        *hidden = self.hidden(position);
+    }
+
+    fn logit(&self, hidden: &policy_net::Hidden, _: &Params, _: &Position, mov: Move) -> f64 {
+        policy_net::Network::logit(self, hidden, mov)
+    }
+}
+
+/// Selected is the policy selected with the engine's options.
+#[derive(Clone)]
+pub enum Selected {
+    Network(Arc<policy_net::Network>),
+    Handcrafted(Handcrafted),
+}
+
+impl Policy for Selected {
+    type State = policy_net::Hidden;
+
+    fn prepare(&self, hidden: &mut policy_net::Hidden, params: &Params, position: &Position) {
+        if let Selected::Network(network) = self {
+            network.prepare(hidden, params, position);
+        }
+    }
+
+    fn logit(
+        &self,
+        hidden: &policy_net::Hidden,
+        params: &Params,
+        position: &Position,
+        mov: Move,
+    ) -> f64 {
+        match self {
+            Selected::Network(network) => network.logit(hidden, params, position, mov),
+            Selected::Handcrafted(_) => handcrafted(params, position, mov),
+        }
+    }
+}
+
+fn handcrafted(params: &Params, position: &Position, mov: Move) -> f64 {
     let mut score = 0.0;
 
     let stm = position.side_to_move;
@@ -10,17 +83,32 @@ pub fn handcrafted(position: &ataxx::Position, mov: Move) -> f64 {
 
     let friends = position.bitboard(stm);
     let enemies = position.bitboard(xtm);
+    let empty = !(friends | enemies | position.bitboard(Piece::Block));
 
+    // Squares adjacent to the source and the target of the move.
     let old_neighbours = BitBoard::single(mov.source());
     let new_neighbours = BitBoard::single(mov.target());
 
-    score += (enemies & new_neighbours).cardinality() as f64 * 1.0;
-    score += (friends & new_neighbours).cardinality() as f64 * 0.4;
+    // Enemy pieces captured by the move and friendly pieces supporting it.
+    score += (enemies & new_neighbours).cardinality() as f64 * params.policy_capture();
+    score += (friends & new_neighbours).cardinality() as f64 * params.policy_support();
+
+    // A piece placed on a square without empty neighbours can't be captured
+    // back, since the opponent has nowhere to move next to it.
+    if (empty & new_neighbours).is_empty() {
+        score += params.policy_hole();
+    }
 
     if mov.is_single() {
-        score += 0.7;
+        score += params.policy_single();
     } else {
-        score -= (friends & old_neighbours).cardinality() as f64 * 0.4;
+        // A jump leaves its source empty, so the friendly pieces next to it
+        // are exposed to being captured if the opponent can reach it.
+        let reachable = BitBoard::single(mov.source()) | BitBoard::double(mov.source());
+        if !(enemies & reachable).is_empty() {
+            let exposed = (friends & old_neighbours).cardinality();
+            score -= exposed as f64 * params.policy_exposed();
+        }
     }
 
     score.max(0.1)
@@ -0,0 +1,138 @@
+//! policy_net implements a neural network which scores the moves of positions
+//! to be used as their priors. The position's pieces, relative to the side to
+//! move, are the inputs of a hidden layer. The logit of a move is the sum of
+//! the dot products of the hidden layer with the weights of the move's source
+//! and target squares, with singular moves sharing a separate source.
+use std::fs;
+
+use ataxx::{Move, Position, Square};
+
+/// HIDDEN is the size of the hidden layer.
+pub const HIDDEN: usize = 32;
+
+/// INPUTS is the number of inputs to the network, one for every colour on
+/// every square, with the colours relative to the side to move.
+pub const INPUTS: usize = 2 * Square::N;
+
+/// SOURCES is the number of move sources, one for every square for jumps and
+/// one shared by all the singular moves.
+pub const SOURCES: usize = Square::N + 1;
+
+/// Network contains the weights of a policy network. It is stored as a
+/// sequence of little endian f32s: the input weights, in input major order,
+/// the hidden biases, the source weights and the target weights, both in
+/// square major order.
+#[derive(Clone)]
+pub struct Network {
+    input_weights: Vec<[f32; HIDDEN]>,
+    hidden_biases: [f32; HIDDEN],
+    source_weights: Vec<[f32; HIDDEN]>,
+    target_weights: Vec<[f32; HIDDEN]>,
+}
+
+/// Hidden is the activated hidden layer of a [Network] for a position.
+pub struct Hidden {
+    vals: [f32; HIDDEN],
+}
+
+impl Default for Hidden {
+    fn default() -> Self {
+        Hidden {
+            vals: [0.0; HIDDEN],
+        }
+    }
+}
+
+impl Network {
+    /// SIZE is the number of bytes in a serialized Network.
+    pub const SIZE: usize = 4 * HIDDEN * (INPUTS + 1 + SOURCES + Square::N);
+
+    /// load reads a serialized Network from the given file.
+    pub fn load(path: &str) -> Result<Network, String> {
+        let bytes = fs::read(path).map_err(|err| format!("{}: {}", path, err))?;
+        Network::from_bytes(&bytes).map_err(|err| format!("{}: {}", path, err))
+    }
+
+    /// from_bytes deserializes a Network from the given bytes.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Network, String> {
+        if bytes.len() != Network::SIZE {
+            return Err(format!(
+                "network has {} bytes, expected {}",
+                bytes.len(),
+                Network::SIZE
+            ));
+        }
+
+        let mut vals = bytes
+            .chunks_exact(4)
+            .map(|chunk| f32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]));
+
+        let mut network = Network {
+            input_weights: vec![[0.0; HIDDEN]; INPUTS],
+            hidden_biases: [0.0; HIDDEN],
+            source_weights: vec![[0.0; HIDDEN]; SOURCES],
+            target_weights: vec![[0.0; HIDDEN]; Square::N],
+        };
+
+        for weights in network.input_weights.iter_mut() {
+            weights.fill_with(|| vals.next().unwrap());
+        }
+
+        network.hidden_biases.fill_with(|| vals.next().unwrap());
+
+        for weights in network.source_weights.iter_mut() {
+            weights.fill_with(|| vals.next().unwrap());
+        }
+
+        for weights in network.target_weights.iter_mut() {
+            weights.fill_with(|| vals.next().unwrap());
+        }
+
+        Ok(network)
+    }
+
+    /// hidden computes the activated hidden layer for the given position.
+    pub fn hidden(&self, position: &Position) -> Hidden {
+        let mut vals = self.hidden_biases;
+
+        let stm = position.side_to_move;
+        for (offset, piece) in [(0, stm), (Square::N, !stm)] {
+            for sq in position.bitboard(piece) {
+                let weights = &self.input_weights[offset + sq as usize];
+                for (val, &weight) in vals.iter_mut().zip(weights) {
+                    *val += weight;
+                }
+            }
+        }
+
+        for val in vals.iter_mut() {
+            *val = val.max(0.0);
+        }
+
+        Hidden { vals }
+    }
+
+    /// logit returns the logit of the given move in the position with the
+    /// given hidden layer.
+    pub fn logit(&self, hidden: &Hidden, mov: Move) -> f64 {
+        if mov == Move::PASS {
+            return 0.0;
+        }
+
+        let source = if mov.is_single() {
+            Square::N
+        } else {
+            mov.source() as usize
+        };
+
+        let source_weights = &self.source_weights[source];
+        let target_weights = &self.target_weights[mov.target() as usize];
+
+        let mut logit = 0.0;
+        for i in 0..HIDDEN {
+            logit += hidden.vals[i] * (source_weights[i] + target_weights[i]);
+        }
+
+        logit as f64
+    }
+}
@@ -0,0 +1,83 @@
+//! table implements a hash table which stores the q values of the positions
+//! searched by the Tree. Since the Tree doesn't know when a position has been
+//! reached through different move orders, the table is used to share the
+//! statistics of transposed positions, by using the stored q value of a
+//! position instead of evaluating it when a new node is reached.
+use std::mem;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use super::Score;
+
+/// Table is a lockless hash table of q values keyed by position hashes. Each
+/// entry stores the upper half of the position's hash to detect collisions
+/// and the q value of the position, from the side to move's perspective.
+pub struct Table {
+    entries: Vec<AtomicU64>,
+}
+
+impl Table {
+    /// new_kib creates a new Table which uses the given number of kibibytes
+    /// of memory for storing its entries.
+    pub fn new_kib(kib: usize) -> Table {
+        let len = (1024 * kib / mem::size_of::<AtomicU64>()).max(1);
+        Table {
+            entries: (0..len).map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+
+    /// resize_kib reallocates the Table with the given number of kibibytes of
+    /// memory for storing its entries. All the entries in the Table are cleared.
+    pub fn resize_kib(&mut self, kib: usize) {
+        // Free the old storage before allocating the new one.
+        self.entries = Vec::new();
+        *self = Table::new_kib(kib);
+    }
+
+    /// clear removes all the entries from the Table.
+    pub fn clear(&mut self) {
+        for entry in self.entries.iter_mut() {
+            *entry.get_mut() = 0;
+        }
+    }
+
+    /// get returns the q value stored for the position with the given hash,
+    /// if there is one.
+    pub fn get(&self, hash: ataxx::Hash) -> Option<Score> {
+        let (index, key) = self.split(hash);
+        let entry = self.entries[index].load(Ordering::Relaxed);
+
+        if entry == 0 || (entry >> 32) as u32 != key {
+            return None;
+        }
+
+        Some(f32::from_bits(entry as u32) as Score)
+    }
+
+    /// set stores the given q value for the position with the given hash,
+    /// replacing any value previously stored in its entry.
+    pub fn set(&self, hash: ataxx::Hash, q: Score) {
+        let (index, key) = self.split(hash);
+        let entry = (key as u64) << 32 | (q as f32).to_bits() as u64;
+
+        self.entries[index].store(entry, Ordering::Relaxed);
+    }
+
+    /// split splits the given hash into the index of its entry and the key
+    /// stored in the entry to identify it.
+    fn split(&self, hash: ataxx::Hash) -> (usize, u32) {
+        let hash: u64 = hash.into();
+        (hash as usize % self.entries.len(), (hash >> 32) as u32)
+    }
+}
+
+impl Clone for Table {
+    fn clone(&self) -> Self {
+        Table {
+            entries: self
+                .entries
+                .iter()
+                .map(|entry| AtomicU64::new(entry.load(Ordering::Relaxed)))
+                .collect(),
+        }
+    }
+}
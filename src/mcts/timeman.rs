@@ -0,0 +1,180 @@
+//! timeman implements the time management of the search, which decides how
+//! long a move should be searched for given the time control of the game.
+use ataxx::{BitBoard, Piece};
+
+use super::Limits;
+
+// Bounds on the estimated number of moves left for the side to move.
+const MIN_MOVES_LEFT: usize = 10;
+const MAX_MOVES_LEFT: usize = 30;
+
+// Ratio of the hard limit to the soft limit, and the inverse of the maximum
+// fraction of the remaining time that may be used on a single move.
+const HARD_RATIO: u128 = 3;
+const MAX_USAGE_DIVISOR: u128 = 2;
+
+// The best move needs to have been stable for this fraction of the soft limit
+// for the search to be stopped at the soft limit.
+const STABILITY_DIVISOR: u128 = 4;
+
+/// Manager decides when a search with a time limit should be stopped.
+pub struct Manager {
+    soft: u128, // Time after which the search stops if the best move is stable.
+    hard: u128, // Time after which the search is always stopped.
+
+    best: ataxx::Move, // Best move, by visits, at the last check.
+    since: u128,       // Time since which the best move has been the same.
+}
+
+impl Manager {
+    /// new creates a new time Manager for searching the given position with
+    /// the given limits. It returns [`None`] if the search has no time limit.
+    /// The overhead is the time lost in communication for every move.
+    pub fn new(limits: &Limits, overhead: u128, position: &ataxx::Position) -> Option<Manager> {
+        let (soft, hard) = if let Some(movetime) = limits.movetime {
+            let time = movetime.saturating_sub(overhead).max(1);
+            (time, time)
+        } else if let Some(time) = limits.time {
+            let time = time.saturating_sub(overhead);
+            let incr = limits.incr.unwrap_or(0);
+
+            // Most moves fill one of the empty squares, so the side to move
+            // has about half as many moves left as there are empty squares.
+            let occupied = position.bitboard(Piece::Black)
+                | position.bitboard(Piece::White)
+                | position.bitboard(Piece::Block);
+            let empty = (BitBoard::UNIVERSE & !occupied).cardinality();
+
+            let mut moves_left = (empty / 2).clamp(MIN_MOVES_LEFT, MAX_MOVES_LEFT);
+            if let Some(movestogo) = limits.movestogo {
+                // The game might end before the next time control.
+                moves_left = moves_left.min(movestogo.max(1));
+            }
+
+            let soft = time / moves_left as u128 + incr * 3 / 4;
+            let hard = (soft * HARD_RATIO).min(time / MAX_USAGE_DIVISOR);
+
+            (soft.min(hard).max(1), hard.max(1))
+        } else {
+            return None;
+        };
+
+        Some(Manager {
+            soft,
+            hard,
+            best: ataxx::Move::NULL,
+            since: 0,
+        })
+    }
+
+    /// should_stop checks if the search should be stopped after searching for
+    /// the given number of milliseconds, given the current best move by visits.
+    /// The search is extended past the soft limit while the best move changes.
+    pub fn should_stop(&mut self, elapsed: u128, best: ataxx::Move) -> bool {
+        if elapsed >= self.hard {
+            return true;
+        }
+
+        if best != self.best {
+            self.best = best;
+            self.since = elapsed;
+        }
+
+        elapsed >= self.soft && elapsed - self.since >= self.soft / STABILITY_DIVISOR
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    /// STARTPOS has 45 empty squares, so 22 moves are estimated to be left.
+    const STARTPOS: &str = "x5o/7/7/7/7/7/o5x x 0 1";
+
+    fn manager(time: u128, incr: u128, movestogo: Option<usize>, overhead: u128) -> Manager {
+        let limits = Limits {
+            maxdepth: None,
+            maxnodes: None,
+            movetime: None,
+            time: Some(time),
+            incr: Some(incr),
+            movestogo,
+            ponder: false,
+        };
+
+        let position = ataxx::Position::from_str(STARTPOS).unwrap();
+        Manager::new(&limits, overhead, &position).unwrap()
+    }
+
+    #[test]
+    fn movestogo() {
+        let timeman = manager(11000, 0, None, 0);
+        assert_eq!((timeman.soft, timeman.hard), (500, 1500));
+
+        // Fewer moves to the next time control leave more time for each.
+        let timeman = manager(11000, 0, Some(5), 0);
+        assert_eq!((timeman.soft, timeman.hard), (2200, 5500));
+
+        // More moves to go than are estimated to be left don't matter.
+        let timeman = manager(11000, 0, Some(40), 0);
+        assert_eq!((timeman.soft, timeman.hard), (500, 1500));
+    }
+
+    #[test]
+    fn move_overhead() {
+        let timeman = manager(12000, 0, None, 1000);
+        assert_eq!((timeman.soft, timeman.hard), (500, 1500));
+
+        let limits = Limits {
+            maxdepth: None,
+            maxnodes: None,
+            movetime: Some(1000),
+            time: None,
+            incr: None,
+            movestogo: None,
+            ponder: false,
+        };
+
+        let position = ataxx::Position::from_str(STARTPOS).unwrap();
+        let timeman = Manager::new(&limits, 50, &position).unwrap();
+        assert_eq!((timeman.soft, timeman.hard), (950, 950));
+
+        // The overhead can't take up all of the time.
+        let timeman = Manager::new(&limits, 5000, &position).unwrap();
+        assert_eq!((timeman.soft, timeman.hard), (1, 1));
+    }
+
+    #[test]
+    fn hard_limit_cap() {
+        // A large increment can't make a move use most of the remaining time.
+        let timeman = manager(1100, 1000, None, 0);
+        assert_eq!((timeman.soft, timeman.hard), (550, 550));
+    }
+
+    #[test]
+    fn stability_extension() {
+        let a = ataxx::Move::from_str("b6").unwrap();
+        let b = ataxx::Move::from_str("f2").unwrap();
+
+        // The best move has been stable since the start of the search.
+        let mut timeman = manager(11000, 0, None, 0);
+        assert!(!timeman.should_stop(0, a));
+        assert!(!timeman.should_stop(499, a));
+        assert!(timeman.should_stop(500, a));
+
+        // The best move changed just before the soft limit, so the search is
+        // extended until it has been stable for a quarter of the soft limit.
+        let mut timeman = manager(11000, 0, None, 0);
+        assert!(!timeman.should_stop(0, a));
+        assert!(!timeman.should_stop(450, b));
+        assert!(!timeman.should_stop(574, b));
+        assert!(timeman.should_stop(575, b));
+
+        // The hard limit stops the search even if the best move isn't stable.
+        let mut timeman = manager(11000, 0, None, 0);
+        assert!(!timeman.should_stop(1400, a));
+        assert!(timeman.should_stop(1500, b));
+    }
+}
@@ -0,0 +1,665 @@
+//! halves implements a storage for the Nodes of a Tree which is split into two
+//! halves. New nodes and edges are only ever added to the active half, and the
+//! nodes in the other half are copied into it as they are used by the search.
+//! When the active half fills up, the halves are flipped: the other half is
+//! cleared and becomes the active one, with the root copied into it. This way
+//! only the nodes which were used since the last flip survive it, at the cost
+//! of a single copy per node and flip, instead of reordering the nodes of the
+//! tree every time they are used like the LRU cache does.
+use std::mem;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use derive_more::{Deref, DerefMut};
+
+use super::{Edge, Node, EDGES_PER_NODE, MAX_EDGES, MAX_NODES};
+
+/// RESERVE_FRACTION is the fraction of the active half which is kept free when
+/// the halves are flipped, for the rollouts which are in flight when it fills.
+const RESERVE_FRACTION: usize = 64;
+
+/// Halves is a storage for [Nodes](Node) split into two halves, each with its
+/// own nodes and edges. A pointer to a node is its index in the first half, or
+/// the number of nodes in a half plus its index in the second half, and the
+/// same goes for the ranges of the nodes' edges.
+#[derive(Clone)]
+pub struct Halves {
+    root_edge: Edge, // Root edge of the whole tree.
+    halves: [Half; 2],
+    active: usize, // Index of the half which nodes are added to.
+
+    cap: usize,      // Number of nodes in each half.
+    edge_cap: usize, // Number of edges in each half.
+}
+
+impl Halves {
+    /// new_kib creates new Halves which use at most the given number of
+    /// kibibytes of memory for storing Nodes and their edges.
+    pub fn new_kib(kib: usize) -> Halves {
+        let (cap, edges) = Halves::capacity(kib);
+        Halves::new(cap, edges)
+    }
+
+    /// capacity returns the numbers of Nodes and edges which can be stored in
+    /// each half with the given number of kibibytes of memory, capped to the
+    /// numbers which can be addressed by the pointers and edge indices.
+    fn capacity(kib: usize) -> (usize, usize) {
+        let bytes = 1024 * kib / 2;
+
+        let slot_size = mem::size_of::<Slot>();
+        let edge_size = mem::size_of::<Edge>();
+
+        // Any memory left over after the nodes is used for more edges. The
+        // pointers into the second half come after those of the first one.
+        let cap = (bytes / (slot_size + EDGES_PER_NODE * edge_size))
+            .min(MAX_NODES / 2)
+            .min(MAX_EDGES / 2 / EDGES_PER_NODE);
+        (
+            cap,
+            ((bytes - cap * slot_size) / edge_size).min(MAX_EDGES / 2),
+        )
+    }
+
+    /// resize_kib reallocates the Halves with the given number of kibibytes of
+    /// capacity for storing Nodes. All the nodes in the Halves are cleared.
+    pub fn resize_kib(&mut self, kib: usize) {
+        // Free the old storage before allocating the new one, so that both of
+        // them don't have to be in memory at the same time.
+        self.halves = [Half::new(0, 0), Half::new(0, 0)];
+        *self = Halves::new_kib(kib);
+    }
+
+    /// new creates new Halves, each of which can store the given numbers of
+    /// Nodes and edges.
+    pub fn new(cap: usize, edges: usize) -> Halves {
+        Halves {
+            root_edge: Edge::new(ataxx::Move::NULL),
+            halves: [Half::new(cap, edges), Half::new(cap, edges)],
+            active: 0,
+            cap,
+            edge_cap: edges,
+        }
+    }
+}
+
+impl Halves {
+    /// clear removes all the nodes from both of the halves without
+    /// reallocating them, and makes the first half the active one.
+    pub fn clear(&mut self) {
+        self.root_edge = Edge::new(ataxx::Move::NULL);
+        for half in &mut self.halves {
+            half.slots.clear();
+            half.edges.clear();
+        }
+
+        self.active = 0;
+    }
+
+    /// push adds the given Node to the active half, and links it to the edge of
+    /// its parent which it was reached by. It returns -1 if the active half is
+    /// full, in which case the node isn't added.
+    pub fn push(&mut self, val: Node) -> i32 {
+        let (parent_node, parent_edge) = (val.parent_node, val.parent_edge);
+
+        let node_ptr = self.add(val);
+        if node_ptr != -1 && parent_node != -1 {
+            self.edge_mut(parent_node, parent_edge).ptr = node_ptr;
+            self.node_mut(parent_node).children += 1;
+        }
+
+        node_ptr
+    }
+
+    /// add stores the given Node in the active half without linking it to its
+    /// parent, returning -1 if the active half is full.
+    fn add(&mut self, val: Node) -> i32 {
+        let half = &mut self.halves[self.active];
+        if half.slots.len() == self.cap {
+            return -1;
+        }
+
+        half.slots.push(Slot::new(val));
+        (self.active * self.cap + half.slots.len() - 1) as i32
+    }
+
+    /// promote copies the given node into the active half if it is in the other
+    /// one, so that it survives the next flip. Nodes in use by any search thread
+    /// other than the calling one aren't moved, as that thread still refers to
+    /// them by their old pointers. They are copied by a later rollout instead.
+    /// It returns the pointer to the copy, or the given one if it isn't copied.
+    pub fn promote(&mut self, ptr: i32) -> i32 {
+        if self.half(ptr) != self.active && *self.slot_mut(ptr).pins.get_mut() <= 1 {
+            let copy = self.copy(ptr);
+            if copy != -1 {
+                return copy;
+            }
+        }
+
+        ptr
+    }
+
+    /// copy copies the given node into the active half along with its edges,
+    /// unless they are in the active half already, and moves all the links to
+    /// the node over to the copy. The old node is left behind as garbage. It
+    /// returns the pointer to the copy, or -1 if the active half is full.
+    fn copy(&mut self, ptr: i32) -> i32 {
+        let mut node = self.node(ptr).clone();
+
+        let edges = node.edges();
+        if !edges.is_empty() && self.edge_half(edges.start) != self.active {
+            if self.halves[self.active].edges.len() + edges.len() > self.edge_cap
+                || self.halves[self.active].slots.len() == self.cap
+            {
+                return -1;
+            }
+
+            let [first, second] = &mut self.halves;
+            let (from, to) = match self.active {
+                0 => (second, first),
+                _ => (first, second),
+            };
+
+            let offset = (1 - self.active) * self.edge_cap;
+            let start = self.active * self.edge_cap + to.edges.len();
+            to.edges
+                .extend_from_slice(&from.edges[edges.start - offset..edges.end - offset]);
+            node.set_edges(start..start + edges.len());
+        }
+
+        let (parent_node, parent_edge) = (node.parent_node, node.parent_edge);
+        let copy = self.add(node);
+        if copy == -1 {
+            return -1;
+        }
+
+        if parent_node != -1 {
+            self.edge_mut(parent_node, parent_edge).ptr = copy;
+        }
+
+        for edge_ptr in 0..self.edges(copy).len() as i32 {
+            let child = self.edge(copy, edge_ptr).ptr;
+            if child != -1 {
+                self.node_mut(child).parent_node = copy;
+            }
+        }
+
+        copy
+    }
+
+    /// full checks if the active half has filled up, so that the halves need
+    /// to be flipped. A half with only the root in it is never full.
+    pub fn full(&self) -> bool {
+        let half = &self.halves[self.active];
+        half.slots.len() > 1
+            && (half.slots.len() + self.cap / RESERVE_FRACTION >= self.cap
+                || half.edges.len() + self.edge_cap / RESERVE_FRACTION >= self.edge_cap)
+    }
+
+    /// flip clears the other half and makes it the active one, after removing
+    /// all the links into it, and copies the given root into it. It returns the
+    /// new pointer to the root, and must not be called while any of the nodes
+    /// are in use by the search threads.
+    pub fn flip(&mut self, root: i32) -> i32 {
+        let other = 1 - self.active;
+
+        // Nodes in the active half always have their edges in it too, so the
+        // nodes linked to them are the only ones left with a dangling link.
+        for i in 0..self.halves[self.active].slots.len() {
+            let ptr = (self.active * self.cap + i) as i32;
+            for edge_ptr in 0..self.edges(ptr).len() as i32 {
+                let child = self.edge(ptr, edge_ptr).ptr;
+                if child != -1 && self.half(child) == other {
+                    self.edge_mut(ptr, edge_ptr).ptr = -1;
+                    self.node_mut(ptr).children -= 1;
+                }
+            }
+        }
+
+        let half = &mut self.halves[other];
+        half.slots.clear();
+        half.edges.clear();
+
+        self.active = other;
+
+        let root = self.copy(root);
+        debug_assert_ne!(root, -1);
+        root
+    }
+
+    /// reroot makes the node at the given pointer the root of the tree, with
+    /// the edge leading to it becoming the new root edge, and copies it into
+    /// the active half if it isn't there already. It returns the new pointer
+    /// to the root, or -1 if it couldn't be copied. The other nodes which are
+    /// no longer reachable are left behind as garbage.
+    pub fn reroot(&mut self, root: i32) -> i32 {
+        let node = self.node(root);
+        let (parent_node, parent_edge) = (node.parent_node, node.parent_edge);
+        self.root_edge = self.edge(parent_node, parent_edge).clone();
+        self.root_edge.ptr = -1;
+
+        if parent_node != -1 {
+            self.edge_mut(parent_node, parent_edge).ptr = -1;
+            self.node_mut(parent_node).children -= 1;
+        }
+
+        let node = self.node_mut(root);
+        node.parent_node = -1;
+        node.parent_edge = -1;
+
+        if self.half(root) == self.active {
+            root
+        } else {
+            self.copy(root)
+        }
+    }
+
+    /// pin marks the given node as in use by a search thread, which prevents it
+    /// from being moved until it is unpinned. A node can be pinned by multiple
+    /// threads at once, and it needs to be unpinned by all of them.
+    pub fn pin(&self, ptr: i32) {
+        self.slot(ptr).pins.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// unpin removes a pin previously placed on the given node by [`Self::pin`].
+    pub fn unpin(&self, ptr: i32) {
+        self.slot(ptr).pins.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// expand stores the given edges in the active half as the edges of the
+    /// given node. It returns false if there isn't enough space for them.
+    pub fn expand(&mut self, ptr: i32, edges: &[Edge]) -> bool {
+        let half = &mut self.halves[self.active];
+        if half.edges.len() + edges.len() > self.edge_cap {
+            return false;
+        }
+
+        let start = self.active * self.edge_cap + half.edges.len();
+        half.edges.extend_from_slice(edges);
+        self.node_mut(ptr).set_edges(start..start + edges.len());
+        true
+    }
+}
+
+impl Halves {
+    /// verify checks the structure of the Halves: that the root is in the active
+    /// half, and that the nodes reachable from it are stored in the halves with
+    /// their edges, are linked to their children both ways and are counted
+    /// correctly. The given node must be the root.
+    pub fn verify(&self, root: i32) -> Result<(), String> {
+        for half in &self.halves {
+            if half.slots.len() > self.cap || half.edges.len() > self.edge_cap {
+                return Err("half holds more than its capacity".to_string());
+            }
+        }
+
+        if self.root_edge.ptr != -1 {
+            return Err("root edge is linked to a node".to_string());
+        }
+
+        if !self.stored(root) || self.half(root) != self.active {
+            return Err(format!("root {} isn't in the active half", root));
+        }
+
+        if self.node(root).parent_node != -1 {
+            return Err(format!("root {} has a parent", root));
+        }
+
+        let mut reached = [
+            vec![false; self.halves[0].slots.len()],
+            vec![false; self.halves[1].slots.len()],
+        ];
+
+        let mut stack = vec![root];
+        while let Some(ptr) = stack.pop() {
+            let (half, index) = (self.half(ptr), self.index(ptr));
+            if reached[half][index] {
+                return Err(format!("node {} is reached twice", ptr));
+            }
+
+            reached[half][index] = true;
+
+            let node = self.node(ptr);
+            let edges = node.edges();
+            if !edges.is_empty() {
+                let edge_half = self.edge_half(edges.start);
+                let offset = edge_half * self.edge_cap;
+                if edges.end - offset > self.halves[edge_half].edges.len() {
+                    return Err(format!("edges of node {} are out of their half", ptr));
+                }
+
+                if half == self.active && edge_half != self.active {
+                    return Err(format!("edges of node {} aren't in its half", ptr));
+                }
+            }
+
+            let mut children = 0;
+            for (i, edge) in self.edges(ptr).iter().enumerate() {
+                if edge.ptr == -1 {
+                    continue;
+                }
+
+                if !self.stored(edge.ptr) {
+                    return Err(format!("node {} has a cleared child {}", ptr, edge.ptr));
+                }
+
+                let child = self.node(edge.ptr);
+                if child.parent_node != ptr || child.parent_edge != i as i32 {
+                    return Err(format!("child {} doesn't link back to {}", edge.ptr, ptr));
+                }
+
+                children += 1;
+                stack.push(edge.ptr);
+            }
+
+            if node.children() != children {
+                return Err(format!(
+                    "node {} has {} children, not {}",
+                    ptr,
+                    children,
+                    node.children()
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// stored checks if the given pointer points to a node stored in a half.
+    fn stored(&self, ptr: i32) -> bool {
+        (0..2 * self.cap as i32).contains(&ptr)
+            && self.index(ptr) < self.halves[self.half(ptr)].slots.len()
+    }
+}
+
+impl Halves {
+    /// node returns a reference to the Node at the given pointer.
+    pub fn node(&self, ptr: i32) -> &Node {
+        self.slot(ptr)
+    }
+
+    /// node_mut returns a mutable reference to the Node at the given pointer.
+    pub fn node_mut(&mut self, ptr: i32) -> &mut Node {
+        self.slot_mut(ptr)
+    }
+
+    /// edges returns the edges of the Node at the given pointer.
+    pub fn edges(&self, ptr: i32) -> &[Edge] {
+        let edges = self.node(ptr).edges();
+        if edges.is_empty() {
+            return &[];
+        }
+
+        let half = self.edge_half(edges.start);
+        let offset = half * self.edge_cap;
+        &self.halves[half].edges[edges.start - offset..edges.end - offset]
+    }
+
+    pub fn edge(&self, parent: i32, edge_ptr: i32) -> &Edge {
+        if parent == -1 {
+            &self.root_edge
+        } else {
+            let (half, index) = self.edge_index(parent, edge_ptr);
+            &self.halves[half].edges[index]
+        }
+    }
+
+    pub fn edge_mut(&mut self, parent: i32, edge_ptr: i32) -> &mut Edge {
+        if parent == -1 {
+            &mut self.root_edge
+        } else {
+            let (half, index) = self.edge_index(parent, edge_ptr);
+            &mut self.halves[half].edges[index]
+        }
+    }
+
+    fn slot(&self, ptr: i32) -> &Slot {
+        &self.halves[self.half(ptr)].slots[self.index(ptr)]
+    }
+
+    fn slot_mut(&mut self, ptr: i32) -> &mut Slot {
+        let (half, index) = (self.half(ptr), self.index(ptr));
+        &mut self.halves[half].slots[index]
+    }
+
+    /// half returns the half which the node at the given pointer is in.
+    fn half(&self, ptr: i32) -> usize {
+        (ptr as usize >= self.cap) as usize
+    }
+
+    /// index returns the index of the node at the given pointer in its half.
+    fn index(&self, ptr: i32) -> usize {
+        ptr as usize - self.half(ptr) * self.cap
+    }
+
+    /// edge_half returns the half which the edge at the given index is in.
+    fn edge_half(&self, index: usize) -> usize {
+        (index >= self.edge_cap) as usize
+    }
+
+    /// edge_index returns the half and the index in that half of the given
+    /// edge of the Node at the given pointer.
+    fn edge_index(&self, parent: i32, edge_ptr: i32) -> (usize, usize) {
+        let edges = self.node(parent).edges();
+        debug_assert!((edge_ptr as usize) < edges.len());
+
+        let index = edges.start + edge_ptr as usize;
+        let half = self.edge_half(index);
+        (half, index - half * self.edge_cap)
+    }
+}
+
+/// Half is one of the two halves of the storage. Its nodes and edges are only
+/// added to its end, and never grow beyond their initial capacities.
+#[derive(Clone)]
+struct Half {
+    slots: Vec<Slot>,
+    edges: Vec<Edge>,
+}
+
+impl Half {
+    fn new(cap: usize, edges: usize) -> Half {
+        Half {
+            slots: Vec::with_capacity(cap),
+            edges: Vec::with_capacity(edges),
+        }
+    }
+}
+
+/// Slot is the storage of a single [Node] in one of the halves.
+#[derive(Deref, DerefMut)]
+struct Slot {
+    #[deref]
+    #[deref_mut]
+    val: Node,
+
+    // Number of search threads currently using this node.
+    pins: AtomicU32,
+}
+
+impl Slot {
+    fn new(val: Node) -> Slot {
+        Slot {
+            val,
+            pins: AtomicU32::new(0),
+        }
+    }
+}
+
+impl Clone for Slot {
+    fn clone(&self) -> Self {
+        Slot {
+            val: self.val.clone(),
+            pins: AtomicU32::new(self.pins.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mcts::tree::fixtures::{edges, tags, tags_of};
+
+    /// tree returns Halves holding a root with two edges, whose second edge
+    /// is linked to a child with three edges, whose first edge is linked to a
+    /// grandchild. It returns the pointers to the three nodes along with it.
+    fn tree() -> (Halves, i32, i32, i32) {
+        let mut halves = Halves::new(4, 16);
+        let root = halves.push(Node::default());
+        assert!(halves.expand(root, &edges(0, 2)));
+
+        let child = halves.push(Node::new(root, 1));
+        assert!(halves.expand(child, &edges(2, 3)));
+
+        let grandchild = halves.push(Node::new(child, 0));
+        assert_eq!(halves.verify(root), Ok(()));
+        (halves, root, child, grandchild)
+    }
+
+    #[test]
+    fn push_fills_active_half() {
+        let mut halves = Halves::new(3, 0);
+        for ptr in 0..3 {
+            assert_eq!(halves.push(Node::default()), ptr);
+        }
+
+        assert_eq!(halves.push(Node::default()), -1);
+        assert!(halves.full());
+    }
+
+    #[test]
+    fn flip_copies_root() {
+        let (mut halves, root, child, _) = tree();
+
+        let root = halves.flip(root);
+        assert_eq!(root, 4);
+        assert_eq!(tags(halves.edges(root)), tags_of(0, 2));
+
+        // The root's child is left in the other half, but is still linked.
+        assert_eq!(halves.edge(root, 1).ptr, child);
+        assert_eq!(halves.node(child).parent_node, root);
+        assert_eq!(halves.verify(root), Ok(()));
+    }
+
+    #[test]
+    fn flip_cuts_uncopied_nodes() {
+        let (mut halves, root, _, _) = tree();
+
+        let root = halves.flip(root);
+        let root = halves.flip(root);
+        assert_eq!(root, 0);
+        assert_eq!(halves.edge(root, 1).ptr, -1);
+        assert_eq!(halves.node(root).children(), 0);
+        assert_eq!(halves.halves[0].slots.len(), 1);
+        assert_eq!(halves.verify(root), Ok(()));
+    }
+
+    #[test]
+    fn promote_copies_node() {
+        let (mut halves, root, child, grandchild) = tree();
+        let root = halves.flip(root);
+
+        halves.promote(child);
+        let copy = halves.edge(root, 1).ptr;
+        assert_eq!(copy, 5);
+        assert_eq!(tags(halves.edges(copy)), tags_of(2, 3));
+        assert_eq!(halves.node(grandchild).parent_node, copy);
+        assert_eq!(halves.verify(root), Ok(()));
+
+        // Nodes in the active half are never copied.
+        halves.promote(copy);
+        assert_eq!(halves.edge(root, 1).ptr, copy);
+
+        // The copied child survives the next flip, but the grandchild doesn't.
+        let root = halves.flip(root);
+        let copy = halves.edge(root, 1).ptr;
+        assert_eq!(tags(halves.edges(copy)), tags_of(2, 3));
+        assert_eq!(halves.edge(copy, 0).ptr, -1);
+        assert_eq!(halves.verify(root), Ok(()));
+    }
+
+    #[test]
+    fn promote_skips_shared_nodes() {
+        let (mut halves, root, child, _) = tree();
+        let root = halves.flip(root);
+
+        halves.pin(child);
+        halves.pin(child);
+        halves.promote(child);
+        assert_eq!(halves.edge(root, 1).ptr, child);
+
+        halves.unpin(child);
+        halves.promote(child);
+        assert_ne!(halves.edge(root, 1).ptr, child);
+        assert_eq!(halves.verify(root), Ok(()));
+    }
+
+    #[test]
+    fn expand_keeps_active_edges() {
+        let (mut halves, root, child, grandchild) = tree();
+        let root = halves.flip(root);
+
+        // The grandchild is expanded into the active half, and its edges are
+        // kept there when it is copied.
+        assert!(halves.expand(grandchild, &edges(5, 2)));
+        let edges = halves.node(grandchild).edges();
+        halves.promote(grandchild);
+        halves.promote(child);
+
+        let copy = halves.edge(halves.edge(root, 1).ptr, 0).ptr;
+        assert_eq!(halves.node(copy).edges(), edges);
+        assert_eq!(tags(halves.edges(copy)), tags_of(5, 2));
+        assert_eq!(halves.verify(root), Ok(()));
+    }
+
+    #[test]
+    fn reroot_copies_root() {
+        let (mut halves, root, child, grandchild) = tree();
+        halves.edge(root, 1).add_virtual_loss();
+        halves.flip(root);
+
+        // The child is still in the other half after the flip.
+        let root = halves.reroot(child);
+        assert_eq!(root, 5);
+        assert_eq!(halves.edge(-1, -1).visits(), 1);
+        assert_eq!(halves.node(grandchild).parent_node, root);
+        assert_eq!(halves.verify(root), Ok(()));
+    }
+
+    #[test]
+    fn new_kib_fits_budget() {
+        let halves = Halves::new_kib(16 * 1024);
+        let bytes =
+            2 * (halves.cap * mem::size_of::<Slot>() + halves.edge_cap * mem::size_of::<Edge>());
+        assert!(bytes <= 16 * 1024 * 1024);
+        assert!(bytes + 2 * mem::size_of::<Edge>() > 16 * 1024 * 1024);
+    }
+
+    #[test]
+    fn capacity_fits_pointers() {
+        // The pointers into the second half come after those of the first.
+        let fits = |kib: usize| {
+            let (cap, edges) = Halves::capacity(kib);
+            i32::try_from(2 * cap - 1).is_ok() && u32::try_from(2 * edges - 1).is_ok()
+        };
+
+        // Whether all of the budget is used, without capping the capacity.
+        let uncapped = |kib: usize| {
+            let (cap, edges) = Halves::capacity(kib);
+            2 * (cap * mem::size_of::<Slot>() + (edges + 1) * mem::size_of::<Edge>()) > kib * 1024
+        };
+
+        // Find the largest budget which isn't capped, up to the 32 TiB which
+        // the Hash option allows.
+        let (mut lo, mut hi) = (1, 32 << 30);
+        while hi - lo > 1 {
+            let mid = (lo + hi) / 2;
+            if uncapped(mid) {
+                lo = mid;
+            } else {
+                hi = mid;
+            }
+        }
+
+        assert!(uncapped(lo) && !uncapped(hi));
+        assert!(fits(lo) && fits(hi) && fits(32 << 30));
+    }
+}
@@ -1,55 +1,111 @@
 //! lru implements a Least Recently Used cache for Nodes. This is used to store
 //! the nodes of a Tree, allowing arbitrarily long searches as unused memory is
-//! continuously freed by the cache.
+//! continuously freed by the cache. The edges of the Nodes are stored in an
+//! arena alongside the cache, which is compacted when it runs out of space.
 use std::mem;
+use std::sync::atomic::{AtomicU32, Ordering};
 
 use derive_more::{Deref, DerefMut};
 use derive_new::new;
 
-use super::{Edge, Node};
+use super::{Edge, Node, EDGES_PER_NODE, MAX_EDGES, MAX_NODES};
+
+/// COLLECTION_FRACTION is the fraction of the arena which is freed every time
+/// it is compacted, so that the cost of compaction is spread over many edges.
+const COLLECTION_FRACTION: usize = 16;
 
 /// Cache is a Least Recently Used (LRU) Cache for [Nodes](Node), which allows
 /// the search tree to utilize limited memory efficiently.
 #[derive(Clone)]
 pub struct Cache {
-    root_edge: Edge, // Root edge of the whole tree.
-    map: Vec<Entry>, // Backing storage of the cache.
+    root_edge: Edge,  // Root edge of the whole tree.
+    map: Vec<Entry>,  // Backing storage of the cache.
+    arena: Vec<Edge>, // Backing storage of the edges of the entries.
 
     cap: usize,
+    edge_cap: usize,
 
     void: i32, // Pointer to the first void in the cache.
+    free: i32, // Pointer to the first entry purged to make space for edges.
     head: i32, // Pointer to the most recently used entry.
     tail: i32, // Pointer to the least recently used entry.
+
+    dead: usize, // Number of edges in the arena which belong to purged entries.
 }
 
 impl Cache {
-    /// new_mib creates a new Cache with the given number of mebibytes of
-    /// capacity for storing Nodes. Note that the actual memory usage will not
-    /// be exactly the provided capacity as the memory used by edges cannot be
-    /// determined but only guessed from the number of nodes.
-    pub fn new_mib(mib: usize) -> Cache {
-        // The / 60 is an adjustment to allow space for the edges.
-        Cache::new(1024 * 1024 * mib / mem::size_of::<Entry>() / 60)
+    /// new_kib creates a new Cache which uses at most the given number of
+    /// kibibytes of memory for storing Nodes and their edges.
+    pub fn new_kib(kib: usize) -> Cache {
+        let (cap, edges) = Cache::capacity(kib);
+        Cache::new(cap, edges)
+    }
+
+    /// capacity returns the numbers of Nodes and edges which can be stored in
+    /// the given number of kibibytes of memory, capped to the numbers which
+    /// can be addressed by the pointers and edge indices.
+    fn capacity(kib: usize) -> (usize, usize) {
+        let bytes = 1024 * kib;
+
+        let entry_size = mem::size_of::<Entry>();
+        let edge_size = mem::size_of::<Edge>();
+
+        // Any memory left over after the entries is used for more edges.
+        let cap = (bytes / (entry_size + EDGES_PER_NODE * edge_size))
+            .min(MAX_NODES)
+            .min(MAX_EDGES / EDGES_PER_NODE);
+        (cap, ((bytes - cap * entry_size) / edge_size).min(MAX_EDGES))
+    }
+
+    /// resize_kib reallocates the Cache with the given number of kibibytes of
+    /// capacity for storing Nodes. All the entries in the Cache are cleared.
+    pub fn resize_kib(&mut self, kib: usize) {
+        // Free the old storage before allocating the new one, so that both of
+        // them don't have to be in memory at the same time.
+        self.map = Vec::new();
+        self.arena = Vec::new();
+        *self = Cache::new_kib(kib);
     }
 
-    /// new creates a new Cache with the given capacity for storing Nodes.
-    pub fn new(cap: usize) -> Cache {
+    /// new creates a new Cache with the given capacity for storing Nodes, and
+    /// an arena with the given capacity for storing their edges.
+    pub fn new(cap: usize, edges: usize) -> Cache {
         Cache {
             map: vec![Entry::new(); cap],
+            // The arena is filled up as nodes are expanded, and never grows
+            // beyond its initial capacity.
+            arena: Vec::with_capacity(edges),
             root_edge: Edge::new(ataxx::Move::NULL),
             cap,
+            edge_cap: edges,
             void: 0,  // The first (0) entry is currently a void.
+            free: -1, // Currently there are no purged entries.
             head: -1, // Currently there is no most recently used entry.
             tail: -1, // Currently there is no least recently used entry.
+            dead: 0,
         }
     }
 }
 
 impl Cache {
-    /// promote makes the given Entry the most recently used one.
-    pub fn promote(&mut self, ptr: i32) {
+    /// clear removes all the entries from the Cache without reallocating it.
+    /// The data of the old entries is only dropped when they are overwritten.
+    pub fn clear(&mut self) {
+        self.root_edge = Edge::new(ataxx::Move::NULL);
+        self.void = 0;
+        self.free = -1;
+        self.head = -1;
+        self.tail = -1;
+        self.arena.clear();
+        self.dead = 0;
+    }
+
+    /// promote makes the given Entry the most recently used one. It returns
+    /// the given pointer, since entries aren't moved by being promoted.
+    pub fn promote(&mut self, ptr: i32) -> i32 {
         self.detach(ptr);
         self.attach(ptr);
+        ptr
     }
 
     /// attach adds the given entry to the non-void list and makes it the head.
@@ -57,6 +113,9 @@ impl Cache {
         // Update old head's links if there is one.
         if self.head != -1 {
             self.node_mut(self.head).prev = ptr;
+        } else {
+            // The list was empty, so the new entry is also its tail.
+            self.tail = ptr;
         }
 
         // Create a copy of the pointer to the old head and make the attached
@@ -70,8 +129,37 @@ impl Cache {
         node.prev = -1;
     }
 
-    /// push adds the given Node to the cache as its head.
+    /// append adds the given entry to the non-void list and makes it the tail.
+    fn append(&mut self, ptr: i32) {
+        // Update old tail's links if there is one.
+        if self.tail != -1 {
+            self.node_mut(self.tail).next = ptr;
+        } else {
+            // The list was empty, so the new entry is also its head.
+            self.head = ptr;
+        }
+
+        // Create a copy of the pointer to the old tail and make the appended
+        // pointer the new tail (least recently used entry) of the cache.
+        let tail_ptr = self.tail;
+        self.tail = ptr;
+
+        // Update the new tail's links.
+        let node = self.node_mut(ptr);
+        node.prev = tail_ptr;
+        node.next = -1;
+    }
+
+    /// push adds the given Node to the cache as its head, and links it to the
+    /// edge of its parent which it was reached by. It returns -1 if the cache
+    /// is full and none of its entries can be purged.
     pub fn push(&mut self, val: Node) -> i32 {
+        // Make sure that the parent isn't purged to make space for its child.
+        let (parent_node, parent_edge) = (val.parent_node, val.parent_edge);
+        if parent_node != -1 {
+            self.pin(parent_node);
+        }
+
         // Find an Entry to store the node in.
         let node_ptr = if (self.void as usize) < self.cap {
             // Void Entry found, so we will use that. This pointer will no
@@ -80,16 +168,35 @@ impl Cache {
             self.void += 1;
             // Return the pointer to the void Entry.
             self.void - 1
+        } else if self.free != -1 {
+            // Reuse an entry which was purged to make space for edges.
+            let ptr = self.free;
+            self.free = self.node(ptr).next;
+            ptr
         } else {
             // No void spots left, so purge the least recently used entry (also
             // called the tail of the cache) and use that spot for storage.
             self.remove_lru()
         };
 
+        if parent_node != -1 {
+            self.unpin(parent_node);
+        }
+
+        // No entry could be purged to make space for the new one.
+        if node_ptr == -1 {
+            return -1;
+        }
+
         // Update the value of the entry and attach it to the cache.
         self.node_mut(node_ptr).val = val;
         self.attach(node_ptr);
 
+        if parent_node != -1 {
+            self.edge_mut(parent_node, parent_edge).ptr = node_ptr;
+            self.node_mut(parent_node).children += 1;
+        }
+
         // Return the pointer to the newly added entry.
         node_ptr
     }
@@ -119,23 +226,332 @@ impl Cache {
         }
     }
 
-    /// remove_lru purges the data of the Least Recently Used Entry, removes all
-    /// links to it, and detaches it from the used LRU cache space. It returns
-    /// the pointer to the purged Entry.
+    /// remove_lru purges the data of the Least Recently Used Entry which isn't
+    /// pinned and doesn't have any children, removes all links to it, and
+    /// detaches it from the used LRU cache space. It returns the pointer to the
+    /// purged Entry, or -1 if none of the entries in the cache can be purged.
+    ///
+    /// Since only entries without children are purged, subtrees are always
+    /// purged bottom-up and no entry is left with a dangling parent. Since the
+    /// search threads pin the entries on the paths of their rollouts, none of
+    /// the entries they are using can be purged either.
     fn remove_lru(&mut self) -> i32 {
-        let tail = self.tail;
+        // Skip over the entries at the end of the cache which can't be purged.
+        // Pinned entries form paths from the root, and parents are always used
+        // more recently than their children, so this rarely skips any others.
+        let mut tail = self.tail;
+        while tail != -1 && (self.node(tail).pinned() || self.node(tail).children > 0) {
+            tail = self.node(tail).prev;
+        }
+
+        if tail == -1 {
+            return -1;
+        }
+
         let node = self.node(tail);
 
-        // Remove all links to the detached Entry.
+        // Remove all links to the detached Entry. The root and the entries
+        // orphaned by rerooting the tree don't have a parent to unlink from.
         let (parent_node, parent_edge) = (node.parent_node, node.parent_edge);
-        self.edge_mut(parent_node, parent_edge).ptr = -1;
+        if parent_node != -1 {
+            self.edge_mut(parent_node, parent_edge).ptr = -1;
+            self.node_mut(parent_node).children -= 1;
+        }
 
-        // Detach the Entry.
+        // Detach the Entry, whose edges are now garbage in the arena.
         self.detach(tail);
+        self.dead += self.node(tail).edges().len();
+        self.node_mut(tail).val = Node::default();
 
         // Return the pointer to the purged LRU entry.
         tail
     }
+
+    /// reroot makes the entry at the given pointer the root of the tree stored
+    /// in the cache, with the edge leading to it becoming the new root edge.
+    /// Entries which are not a part of the new root's subtree are orphaned and
+    /// moved to the end of the cache so that they are purged first. They keep
+    /// their links to each other, so that they can still be purged bottom-up.
+    pub fn reroot(&mut self, root: i32) {
+        // Find all the entries which are reachable from the new root.
+        let mut reachable = vec![false; self.cap];
+        let mut stack = vec![root];
+        while let Some(ptr) = stack.pop() {
+            reachable[ptr as usize] = true;
+            for edge in self.edges(ptr) {
+                if edge.ptr != -1 {
+                    stack.push(edge.ptr);
+                }
+            }
+        }
+
+        // Make the edge leading to the new root the root edge of the tree, and
+        // unlink the new root from its old parent.
+        let node = self.node(root);
+        let (parent_node, parent_edge) = (node.parent_node, node.parent_edge);
+        self.root_edge = self.edge(parent_node, parent_edge).clone();
+        self.root_edge.ptr = -1;
+
+        if parent_node != -1 {
+            self.edge_mut(parent_node, parent_edge).ptr = -1;
+            self.node_mut(parent_node).children -= 1;
+        }
+
+        let node = self.node_mut(root);
+        node.parent_node = -1;
+        node.parent_edge = -1;
+
+        // Collect the entries from the most to the least recently used one,
+        // since the list will be modified while moving the entries.
+        let mut entries = vec![];
+        let mut ptr = self.head;
+        while ptr != -1 {
+            entries.push(ptr);
+            ptr = self.node(ptr).next;
+        }
+
+        // Orphan the unreachable entries and move them to the end of the cache
+        // while maintaining their relative order.
+        for ptr in entries {
+            if reachable[ptr as usize] {
+                continue;
+            }
+
+            self.detach(ptr);
+            self.append(ptr);
+        }
+
+        self.promote(root);
+    }
+
+    /// pin marks the given entry as in use by a search thread, which prevents
+    /// it from being purged until it is unpinned. An entry can be pinned by
+    /// multiple threads at once, and it needs to be unpinned by all of them.
+    pub fn pin(&self, ptr: i32) {
+        self.node(ptr).pins.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// unpin removes a pin previously placed on the given entry by [`Self::pin`].
+    pub fn unpin(&self, ptr: i32) {
+        self.node(ptr).pins.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Cache {
+    /// expand stores the given edges in the arena as the edges of the given
+    /// entry. If the arena is full, space is made by purging the least recently
+    /// used entries and compacting it. It returns false if no space could be
+    /// made for the edges, as all of the entries in the cache are pinned.
+    pub fn expand(&mut self, ptr: i32, edges: &[Edge]) -> bool {
+        if self.arena.len() + edges.len() > self.edge_cap {
+            // Make sure that the entry isn't purged to make space for itself.
+            self.pin(ptr);
+            self.collect(edges.len());
+            self.unpin(ptr);
+
+            if self.arena.len() + edges.len() > self.edge_cap {
+                return false;
+            }
+        }
+
+        let range = self.arena.len()..self.arena.len() + edges.len();
+        self.arena.extend_from_slice(edges);
+        self.node_mut(ptr).set_edges(range);
+        true
+    }
+
+    /// collect frees space for at least the given number of edges in the arena.
+    /// The least recently used entries are purged until a sizeable fraction of
+    /// the arena is garbage, which is then removed by compacting the arena.
+    fn collect(&mut self, edges: usize) {
+        let target = edges.max(self.edge_cap / COLLECTION_FRACTION);
+        while self.dead + (self.edge_cap - self.arena.len()) < target {
+            let ptr = self.remove_lru();
+            if ptr == -1 {
+                break;
+            }
+
+            // Keep the purged entry around to be reused by the next push.
+            self.node_mut(ptr).next = self.free;
+            self.free = ptr;
+        }
+
+        self.compact();
+    }
+
+    /// compact moves the edges of all the entries to the start of the arena,
+    /// keeping their relative order, so that all the garbage is at its end.
+    fn compact(&mut self) {
+        let mut entries = vec![];
+        let mut ptr = self.head;
+        while ptr != -1 {
+            let node = self.node(ptr);
+            if node.expanded() {
+                entries.push((node.edges().start, ptr));
+            }
+
+            ptr = node.next;
+        }
+
+        entries.sort_unstable();
+
+        // Every entry's edges are moved down to the end of the previous one's,
+        // which never overlaps with the edges of the entries after it.
+        let mut top = 0;
+        for (start, ptr) in entries {
+            let len = self.node(ptr).edges().len();
+            if start != top {
+                for i in 0..len {
+                    self.arena.swap(top + i, start + i);
+                }
+            }
+
+            self.node_mut(ptr).set_edges(top..top + len);
+            top += len;
+        }
+
+        self.arena.truncate(top);
+        self.dead = 0;
+    }
+}
+
+impl Cache {
+    /// verify checks the structure of the Cache: the LRU list and the list of
+    /// purged entries, the links between the entries and their children, and
+    /// the accounting of the edge arena. The given entry must be the root.
+    pub fn verify(&self, root: i32) -> Result<(), String> {
+        // Where every entry was found, so that cycles can't go unnoticed.
+        const VOID: u8 = 0;
+        const LISTED: u8 = 1;
+        const FREE: u8 = 2;
+        let mut found = vec![VOID; self.cap];
+
+        let (mut prev, mut ptr, mut listed) = (-1, self.head, 0);
+        while ptr != -1 {
+            if !(0..self.void).contains(&ptr) || found[ptr as usize] != VOID {
+                return Err(format!("entry {} can't be in the list", ptr));
+            }
+
+            let node = self.node(ptr);
+            if node.prev != prev {
+                return Err(format!(
+                    "entry {} comes after {}, not {}",
+                    ptr, prev, node.prev
+                ));
+            }
+
+            found[ptr as usize] = LISTED;
+            listed += 1;
+            prev = ptr;
+            ptr = node.next;
+        }
+
+        if self.tail != prev {
+            return Err(format!(
+                "tail is {}, not the last entry {}",
+                self.tail, prev
+            ));
+        }
+
+        let (mut ptr, mut free) = (self.free, 0);
+        while ptr != -1 {
+            if !(0..self.void).contains(&ptr) || found[ptr as usize] != VOID {
+                return Err(format!("entry {} can't be purged", ptr));
+            }
+
+            found[ptr as usize] = FREE;
+            free += 1;
+            ptr = self.node(ptr).next;
+        }
+
+        if listed + free != self.void as usize {
+            return Err(format!(
+                "{} entries are used, not {}",
+                listed + free,
+                self.void
+            ));
+        }
+
+        if self.root_edge.ptr != -1 {
+            return Err("root edge is linked to a node".to_string());
+        }
+
+        if !(0..self.void).contains(&root) || found[root as usize] != LISTED {
+            return Err(format!("root {} isn't in the list", root));
+        }
+
+        let mut ranges = vec![];
+        for ptr in (0..self.void).filter(|&ptr| found[ptr as usize] == LISTED) {
+            let node = self.node(ptr);
+
+            let parent_node = node.parent_node;
+            if ptr == root && parent_node != -1 {
+                return Err(format!("root {} has a parent", ptr));
+            }
+
+            // The parent's side of the link is checked along with its edges.
+            if parent_node != -1
+                && (!(0..self.void).contains(&parent_node) || found[parent_node as usize] != LISTED)
+            {
+                return Err(format!("entry {} has a purged parent {}", ptr, parent_node));
+            }
+
+            let mut children = 0;
+            for (i, edge) in self.edges(ptr).iter().enumerate() {
+                if edge.ptr == -1 {
+                    continue;
+                }
+
+                if !(0..self.void).contains(&edge.ptr) || found[edge.ptr as usize] != LISTED {
+                    return Err(format!("entry {} has a purged child {}", ptr, edge.ptr));
+                }
+
+                let child = self.node(edge.ptr);
+                if child.parent_node != ptr || child.parent_edge != i as i32 {
+                    return Err(format!("child {} doesn't link back to {}", edge.ptr, ptr));
+                }
+
+                children += 1;
+            }
+
+            if node.children() != children {
+                return Err(format!(
+                    "entry {} has {} children, not {}",
+                    ptr,
+                    children,
+                    node.children()
+                ));
+            }
+
+            let edges = node.edges();
+            if edges.end > self.arena.len() {
+                return Err(format!("edges of entry {} are out of the arena", ptr));
+            }
+
+            if !edges.is_empty() {
+                ranges.push(edges);
+            }
+        }
+
+        // The arena is made up of the edges of the entries and the garbage left
+        // by the purged ones, and its edges are never shared.
+        ranges.sort_unstable_by_key(|edges| edges.start);
+        if ranges.windows(2).any(|pair| pair[0].end > pair[1].start) {
+            return Err("edges of entries overlap in the arena".to_string());
+        }
+
+        let live: usize = ranges.iter().map(|edges| edges.len()).sum();
+        if live + self.dead != self.arena.len() || self.arena.len() > self.edge_cap {
+            return Err(format!(
+                "arena has {} edges, not {} live and {} dead ones",
+                self.arena.len(),
+                live,
+                self.dead
+            ));
+        }
+
+        Ok(())
+    }
 }
 
 impl Cache {
@@ -149,11 +565,16 @@ impl Cache {
         &mut self.map[ptr as usize]
     }
 
+    /// edges returns the edges of the Entry at the given pointer.
+    pub fn edges(&self, ptr: i32) -> &[Edge] {
+        &self.arena[self.node(ptr).edges()]
+    }
+
     pub fn edge(&self, parent: i32, edge_ptr: i32) -> &Edge {
         if parent == -1 {
             &self.root_edge
         } else {
-            self.node(parent).edge(edge_ptr)
+            &self.arena[self.edge_index(parent, edge_ptr)]
         }
     }
 
@@ -161,14 +582,23 @@ impl Cache {
         if parent == -1 {
             &mut self.root_edge
         } else {
-            self.node_mut(parent).edge_mut(edge_ptr)
+            let index = self.edge_index(parent, edge_ptr);
+            &mut self.arena[index]
         }
     }
+
+    /// edge_index returns the index in the arena of the given edge of the
+    /// Entry at the given pointer.
+    fn edge_index(&self, parent: i32, edge_ptr: i32) -> usize {
+        let edges = self.node(parent).edges();
+        debug_assert!((edge_ptr as usize) < edges.len());
+        edges.start + edge_ptr as usize
+    }
 }
 
 /// Entry is one of the entries in the LRU [Cache]. Externally, it is mainly
 /// used by dereferencing it into a [Node] instead of directly using it.
-#[derive(Clone, Deref, DerefMut, new)]
+#[derive(Deref, DerefMut, new)]
 pub struct Entry {
     #[deref]
     #[deref_mut]
@@ -178,4 +608,500 @@ pub struct Entry {
     prev: i32,
     #[new(value = "-1")]
     next: i32,
+
+    // Number of search threads currently using this entry.
+    #[new(default)]
+    pins: AtomicU32,
+}
+
+impl Entry {
+    /// pinned checks if the Entry is currently in use by any search thread.
+    pub fn pinned(&self) -> bool {
+        self.pins.load(Ordering::Relaxed) > 0
+    }
+}
+
+impl Clone for Entry {
+    fn clone(&self) -> Self {
+        Entry {
+            val: self.val.clone(),
+            prev: self.prev,
+            next: self.next,
+            pins: AtomicU32::new(self.pins.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use rand::rngs::StdRng;
+    use rand::seq::SliceRandom;
+    use rand::{Rng, SeedableRng};
+
+    use super::*;
+    use crate::mcts::tree::fixtures::{edges, tags, tags_of};
+    use crate::mcts::tree::Proof;
+
+    /// order returns the entries of the Cache from the most to the least
+    /// recently used one.
+    fn order(cache: &Cache) -> Vec<i32> {
+        let mut order = vec![];
+        let mut ptr = cache.head;
+        while ptr != -1 {
+            order.push(ptr);
+            ptr = cache.node(ptr).next;
+        }
+
+        order
+    }
+
+    #[test]
+    fn push_makes_head() {
+        let mut cache = Cache::new(4, 0);
+        for ptr in 0..3 {
+            assert_eq!(cache.push(Node::default()), ptr);
+        }
+
+        assert_eq!(order(&cache), [2, 1, 0]);
+        assert_eq!((cache.head, cache.tail, cache.void), (2, 0, 3));
+    }
+
+    #[test]
+    fn promote_makes_head() {
+        let mut cache = Cache::new(4, 0);
+        for _ in 0..3 {
+            cache.push(Node::default());
+        }
+
+        cache.promote(0);
+        assert_eq!(order(&cache), [0, 2, 1]);
+
+        cache.promote(2);
+        assert_eq!(order(&cache), [2, 0, 1]);
+
+        cache.promote(2);
+        assert_eq!(order(&cache), [2, 0, 1]);
+    }
+
+    #[test]
+    fn detach_keeps_list() {
+        let mut cache = Cache::new(4, 0);
+        for _ in 0..4 {
+            cache.push(Node::default());
+        }
+
+        cache.detach(2);
+        assert_eq!(order(&cache), [3, 1, 0]);
+
+        cache.detach(3);
+        assert_eq!(order(&cache), [1, 0]);
+        assert_eq!(cache.head, 1);
+
+        cache.detach(0);
+        assert_eq!(order(&cache), [1]);
+        assert_eq!(cache.tail, 1);
+
+        cache.detach(1);
+        assert_eq!(order(&cache), []);
+        assert_eq!((cache.head, cache.tail), (-1, -1));
+    }
+
+    #[test]
+    fn remove_lru_skips_pinned() {
+        let mut cache = Cache::new(3, 0);
+        for _ in 0..3 {
+            cache.push(Node::default());
+        }
+
+        assert_eq!(cache.remove_lru(), 0);
+        assert_eq!(order(&cache), [2, 1]);
+
+        cache.pin(1);
+        assert_eq!(cache.remove_lru(), 2);
+        assert_eq!(order(&cache), [1]);
+
+        assert_eq!(cache.remove_lru(), -1);
+        assert_eq!(order(&cache), [1]);
+
+        cache.unpin(1);
+        assert_eq!(cache.remove_lru(), 1);
+        assert_eq!(order(&cache), []);
+    }
+
+    #[test]
+    fn remove_lru_skips_parents() {
+        let mut cache = Cache::new(3, 8);
+        let root = cache.push(Node::default());
+        assert!(cache.expand(root, &edges(0, 2)));
+
+        let child = cache.push(Node::new(root, 1));
+        assert_eq!(cache.edge(root, 1).ptr, child);
+        assert_eq!(cache.node(root).children(), 1);
+
+        // The root is the least recently used entry, but it has a child.
+        assert_eq!(order(&cache), [child, root]);
+        assert_eq!(cache.remove_lru(), child);
+
+        assert_eq!(cache.edge(root, 1).ptr, -1);
+        assert_eq!(cache.node(root).children(), 0);
+        assert_eq!(cache.remove_lru(), root);
+    }
+
+    #[test]
+    fn push_rolls_over() {
+        let mut cache = Cache::new(2, 0);
+        assert_eq!(cache.push(Node::default()), 0);
+        assert_eq!(cache.push(Node::default()), 1);
+
+        // The least recently used entry is reused once the cache is full.
+        assert_eq!(cache.push(Node::default()), 0);
+        assert_eq!(order(&cache), [0, 1]);
+
+        cache.promote(1);
+        assert_eq!(cache.push(Node::default()), 0);
+        assert_eq!(order(&cache), [0, 1]);
+
+        cache.pin(0);
+        cache.pin(1);
+        assert_eq!(cache.push(Node::default()), -1);
+        assert_eq!(order(&cache), [0, 1]);
+    }
+
+    #[test]
+    fn push_keeps_parent() {
+        let mut cache = Cache::new(1, 4);
+        let root = cache.push(Node::default());
+        assert!(cache.expand(root, &edges(0, 1)));
+
+        // The only entry is the parent of the new one, so it can't be purged.
+        assert_eq!(cache.push(Node::new(root, 0)), -1);
+        assert_eq!(cache.edge(root, 0).ptr, -1);
+        assert_eq!(cache.verify(root), Ok(()));
+    }
+
+    #[test]
+    fn root_edge() {
+        let mut cache = Cache::new(4, 8);
+        let root = cache.push(Node::default());
+
+        assert_eq!(cache.edge(-1, -1).ptr, -1);
+        cache.edge(-1, -1).add_virtual_loss();
+        cache.edge_mut(-1, -1).add_score(1.0);
+        assert_eq!(cache.edge(-1, -1).visits(), 1);
+
+        assert!(cache.expand(root, &edges(0, 2)));
+        let child = cache.push(Node::new(root, 1));
+        cache.edge(root, 1).add_virtual_loss();
+
+        // The edge to the new root becomes the root edge, and the old root is
+        // orphaned to the end of the cache.
+        cache.reroot(child);
+        assert_eq!(cache.edge(-1, -1).proof, Proof::Win(1));
+        assert_eq!(cache.edge(-1, -1).visits(), 1);
+        assert_eq!(cache.edge(-1, -1).ptr, -1);
+        assert_eq!(cache.edge(root, 1).ptr, -1);
+        assert_eq!(order(&cache), [child, root]);
+        assert_eq!(cache.verify(child), Ok(()));
+
+        cache.clear();
+        assert_eq!(cache.edge(-1, -1).visits(), 0);
+        assert_eq!(cache.edge(-1, -1).proof, Proof::Unknown);
+        assert_eq!(order(&cache), []);
+    }
+
+    #[test]
+    fn expand_compacts_arena() {
+        let mut cache = Cache::new(8, 10);
+        let root = cache.push(Node::default());
+        assert!(cache.expand(root, &edges(0, 3)));
+
+        let a = cache.push(Node::new(root, 0));
+        assert!(cache.expand(a, &edges(10, 4)));
+
+        let b = cache.push(Node::new(root, 1));
+        cache.promote(root);
+
+        // The arena is too full for b's edges, so the least recently used
+        // entry which can be purged, a, is purged to make space.
+        assert!(cache.expand(b, &edges(20, 5)));
+        assert_eq!(order(&cache), [root, b]);
+        assert_eq!(cache.edge(root, 0).ptr, -1);
+
+        assert_eq!(tags(cache.edges(root)), tags_of(0, 3));
+        assert_eq!(tags(cache.edges(b)), tags_of(20, 5));
+        assert_eq!(cache.node(root).edges(), 0..3);
+        assert_eq!(cache.node(b).edges(), 3..8);
+        assert_eq!(cache.verify(root), Ok(()));
+
+        // No space can be made if all the entries are in use.
+        let c = cache.push(Node::new(root, 2));
+        cache.pin(b);
+        assert!(!cache.expand(c, &edges(30, 3)));
+        assert!(!cache.node(c).expanded());
+        assert_eq!(cache.verify(root), Ok(()));
+    }
+
+    #[test]
+    fn new_kib_fits_budget() {
+        for kib in [960, 3 * 1024, 16 * 1024] {
+            let cache = Cache::new_kib(kib);
+            let bytes =
+                cache.cap * mem::size_of::<Entry>() + cache.edge_cap * mem::size_of::<Edge>();
+
+            assert!(bytes <= kib * 1024);
+            assert!(bytes + mem::size_of::<Edge>() > kib * 1024);
+        }
+    }
+
+    #[test]
+    fn capacity_fits_pointers() {
+        let fits = |kib: usize| {
+            let (cap, edges) = Cache::capacity(kib);
+            i32::try_from(cap - 1).is_ok() && u32::try_from(edges - 1).is_ok()
+        };
+
+        // Whether all of the budget is used, without capping the capacity.
+        let uncapped = |kib: usize| {
+            let (cap, edges) = Cache::capacity(kib);
+            cap * mem::size_of::<Entry>() + (edges + 1) * mem::size_of::<Edge>() > kib * 1024
+        };
+
+        // Find the largest budget which isn't capped, up to the 32 TiB which
+        // the Hash option allows.
+        let (mut lo, mut hi) = (1, 32 << 30);
+        while hi - lo > 1 {
+            let mid = (lo + hi) / 2;
+            if uncapped(mid) {
+                lo = mid;
+            } else {
+                hi = mid;
+            }
+        }
+
+        assert!(uncapped(lo) && !uncapped(hi));
+        assert!(fits(lo) && fits(hi) && fits(32 << 30));
+    }
+
+    /// Model is a simple reference model of a Cache, which keeps its entries
+    /// in a list ordered from the most to the least recently used one.
+    #[derive(Default)]
+    struct Model {
+        order: Vec<i32>,
+        nodes: HashMap<i32, ModelNode>,
+    }
+
+    struct ModelNode {
+        parent: (i32, i32),
+        edges: Vec<(Proof, i32)>, // Tag and child of every edge.
+        pins: u32,
+    }
+
+    impl Model {
+        /// purgeable returns the entry which should be purged next.
+        fn purgeable(&self) -> Option<i32> {
+            self.order.iter().rev().copied().find(|ptr| {
+                let node = &self.nodes[ptr];
+                node.pins == 0 && node.edges.iter().all(|&(_, child)| child == -1)
+            })
+        }
+
+        fn purge(&mut self, ptr: i32) {
+            let node = self.nodes.remove(&ptr).unwrap();
+            self.order.retain(|&other| other != ptr);
+
+            let (parent, edge) = node.parent;
+            if parent != -1 {
+                self.nodes.get_mut(&parent).unwrap().edges[edge as usize].1 = -1;
+            }
+        }
+
+        fn edges(&self) -> usize {
+            self.nodes.values().map(|node| node.edges.len()).sum()
+        }
+
+        /// check checks that the given Cache matches the Model.
+        fn check(&self, cache: &Cache, root: i32) {
+            assert_eq!(cache.verify(root), Ok(()));
+            assert_eq!(order(cache), self.order);
+
+            for (&ptr, node) in &self.nodes {
+                let entry = cache.node(ptr);
+                assert_eq!((entry.parent_node, entry.parent_edge), node.parent);
+                assert_eq!(entry.pins.load(Ordering::Relaxed), node.pins);
+
+                let edges: Vec<_> = cache.edges(ptr).iter().map(|e| (e.proof, e.ptr)).collect();
+                assert_eq!(edges, node.edges);
+            }
+        }
+    }
+
+    #[test]
+    fn random_operations() {
+        for seed in 0..32 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let (cap, edge_cap) = (rng.gen_range(2..24), rng.gen_range(8..96));
+
+            let mut cache = Cache::new(cap, edge_cap);
+            let mut model = Model::default();
+
+            // The root is always pinned, like it is during a search.
+            let mut root = cache.push(Node::default());
+            cache.pin(root);
+            model.order.push(root);
+            model.nodes.insert(
+                root,
+                ModelNode {
+                    parent: (-1, -1),
+                    edges: vec![],
+                    pins: 1,
+                },
+            );
+
+            let mut tag = 0;
+            for _ in 0..2000 {
+                let ptr = *model.order.choose(&mut rng).unwrap();
+                match rng.gen_range(0..6) {
+                    // Push a child of a random entry.
+                    0 | 1 => {
+                        let node = &model.nodes[&ptr];
+                        let free: Vec<usize> = (0..node.edges.len())
+                            .filter(|&i| node.edges[i].1 == -1)
+                            .collect();
+                        let Some(&edge) = free.choose(&mut rng) else {
+                            continue;
+                        };
+
+                        // The parent can't be purged to make space for its child.
+                        let full = model.nodes.len() == cap;
+                        model.nodes.get_mut(&ptr).unwrap().pins += 1;
+                        let purged = if full { model.purgeable() } else { None };
+                        model.nodes.get_mut(&ptr).unwrap().pins -= 1;
+
+                        let child = cache.push(Node::new(ptr, edge as i32));
+                        if full && purged.is_none() {
+                            assert_eq!(child, -1);
+                            continue;
+                        }
+
+                        if let Some(purged) = purged {
+                            assert_eq!(child, purged);
+                            model.purge(purged);
+                        }
+
+                        assert!(!model.nodes.contains_key(&child));
+                        model.order.insert(0, child);
+                        model.nodes.get_mut(&ptr).unwrap().edges[edge].1 = child;
+                        model.nodes.insert(
+                            child,
+                            ModelNode {
+                                parent: (ptr, edge as i32),
+                                edges: vec![],
+                                pins: 0,
+                            },
+                        );
+                    }
+
+                    // Expand a random entry, which may purge others.
+                    2 => {
+                        if !model.nodes[&ptr].edges.is_empty() {
+                            continue;
+                        }
+
+                        let count = rng.gen_range(1..12);
+                        let expanded = cache.expand(ptr, &edges(tag, count));
+
+                        // The purged entries must have been purged in order.
+                        let listed = order(&cache);
+                        model.nodes.get_mut(&ptr).unwrap().pins += 1;
+                        while model.order.len() > listed.len() {
+                            let purged = model.purgeable().unwrap();
+                            assert!(!listed.contains(&purged));
+                            model.purge(purged);
+                        }
+                        model.nodes.get_mut(&ptr).unwrap().pins -= 1;
+
+                        if expanded {
+                            let edges = tags_of(tag, count).into_iter().map(|tag| (tag, -1));
+                            model.nodes.get_mut(&ptr).unwrap().edges = edges.collect();
+                            tag += count;
+                        } else {
+                            model.nodes.get_mut(&ptr).unwrap().pins += 1;
+                            let stuck = model.purgeable().is_none();
+                            model.nodes.get_mut(&ptr).unwrap().pins -= 1;
+                            assert!(stuck || model.edges() + count as usize > edge_cap);
+                        }
+                    }
+
+                    // Promote a random entry.
+                    3 => {
+                        cache.promote(ptr);
+                        model.order.retain(|&other| other != ptr);
+                        model.order.insert(0, ptr);
+                    }
+
+                    // Pin or unpin a random entry.
+                    4 => {
+                        let node = model.nodes.get_mut(&ptr).unwrap();
+                        let permanent = if ptr == root { 1 } else { 0 };
+                        if node.pins > permanent && rng.gen_bool(0.5) {
+                            cache.unpin(ptr);
+                            node.pins -= 1;
+                        } else if node.pins < 4 {
+                            cache.pin(ptr);
+                            node.pins += 1;
+                        }
+                    }
+
+                    // Reroot the cache to a random child of the root.
+                    _ => {
+                        let children: Vec<_> = model.nodes[&root]
+                            .edges
+                            .iter()
+                            .enumerate()
+                            .filter(|(_, &(_, child))| child != -1)
+                            .map(|(i, &(_, child))| (i, child))
+                            .collect();
+                        let Some(&(edge, child)) = children.choose(&mut rng) else {
+                            continue;
+                        };
+
+                        // Only the root is pinned while rerooting the tree.
+                        if model.nodes.values().map(|node| node.pins).sum::<u32>() != 1 {
+                            continue;
+                        }
+
+                        let mut reachable = vec![child];
+                        let mut i = 0;
+                        while i < reachable.len() {
+                            let node = &model.nodes[&reachable[i]];
+                            reachable.extend(node.edges.iter().map(|e| e.1).filter(|&c| c != -1));
+                            i += 1;
+                        }
+
+                        cache.unpin(root);
+                        cache.reroot(child);
+                        cache.pin(child);
+                        assert_eq!(cache.edge(-1, -1).proof, model.nodes[&root].edges[edge].0);
+
+                        let (kept, orphaned): (Vec<i32>, Vec<i32>) =
+                            model.order.iter().partition(|ptr| reachable.contains(ptr));
+                        model.order = kept.into_iter().filter(|&ptr| ptr != child).collect();
+                        model.order.insert(0, child);
+                        model.order.extend(orphaned);
+
+                        model.nodes.get_mut(&root).unwrap().edges[edge].1 = -1;
+                        model.nodes.get_mut(&root).unwrap().pins -= 1;
+                        let node = model.nodes.get_mut(&child).unwrap();
+                        node.parent = (-1, -1);
+                        node.pins += 1;
+                        root = child;
+                    }
+                }
+
+                model.check(&cache, root);
+            }
+        }
+    }
 }
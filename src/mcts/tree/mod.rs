@@ -1,111 +1,696 @@
-use derive_more::{Deref, DerefMut};
+use std::str::FromStr;
 
 use ataxx::MoveStore;
 
 pub use self::node::*;
 
+mod halves;
 mod lru;
 mod node;
 
-#[derive(Clone, Deref, DerefMut)]
+/// EDGES_PER_NODE is the number of edges stored for every node of a Tree. Most
+/// of the nodes in a tree are leaves which are never expanded, so this is a
+/// little more than the average number of edges of a node.
+const EDGES_PER_NODE: usize = 8;
+
+/// MAX_NODES is the largest number of nodes which can be addressed by the node
+/// pointers of a Tree.
+const MAX_NODES: usize = NodePtr::MAX as usize;
+
+/// MAX_EDGES is the largest number of edges which can be addressed by the edge
+/// indices stored in the nodes of a Tree.
+const MAX_EDGES: usize = u32::MAX as usize;
+
+#[derive(Clone)]
 pub struct Tree {
-    #[deref]
-    #[deref_mut]
-    nodes: lru::Cache,
+    nodes: Nodes,
     root_pos: ataxx::Position,
+    root: NodePtr,
+
+    kib: usize, // Memory budget of the tree in kibibytes.
+}
+
+/// Memory is the strategy with which a Tree manages the memory of its nodes
+/// once all of it is in use.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Memory {
+    /// The least recently used leaves are purged one by one to make space for
+    /// new nodes, with the nodes reordered every time they are used.
+    #[default]
+    Lru,
+    /// The memory is split into two halves, and once the active one fills up
+    /// the nodes still in use are moved into the other one.
+    Halves,
+}
+
+impl FromStr for Memory {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lru" => Ok(Memory::Lru),
+            "halves" => Ok(Memory::Halves),
+            _ => Err(format!("unknown memory strategy {}", s)),
+        }
+    }
+}
+
+/// Nodes is the storage of the nodes of a Tree for each [Memory] strategy.
+#[derive(Clone)]
+enum Nodes {
+    Lru(lru::Cache),
+    Halves(halves::Halves),
+}
+
+/// with_nodes evaluates the given expression with the given name bound to the
+/// storage of the nodes, whichever [Memory] strategy it uses.
+macro_rules! with_nodes {
+    ($nodes:expr, $name:ident => $body:expr) => {
+        match $nodes {
+            Nodes::Lru($name) => $body,
+            Nodes::Halves($name) => $body,
+        }
+    };
+}
+
+impl Nodes {
+    /// new_kib creates a new storage for nodes using the given strategy which
+    /// uses at most the given number of kibibytes of memory.
+    fn new_kib(memory: Memory, kib: usize) -> Nodes {
+        match memory {
+            Memory::Lru => Nodes::Lru(lru::Cache::new_kib(kib)),
+            Memory::Halves => Nodes::Halves(halves::Halves::new_kib(kib)),
+        }
+    }
+
+    fn memory(&self) -> Memory {
+        match self {
+            Nodes::Lru(_) => Memory::Lru,
+            Nodes::Halves(_) => Memory::Halves,
+        }
+    }
+}
+
+/// Line is a principal variation reported by the search, along with the
+/// statistics of its first move.
+pub struct Line {
+    pub pv: Vec<ataxx::Move>,
+    pub score: Score,
+    pub proof: Proof,
 }
 
 impl Tree {
-    pub fn new(position: ataxx::Position) -> Tree {
-        let mut cache = lru::Cache::new_mib(64);
-        cache.push(Default::default());
-        Tree {
+    /// new creates a new Tree rooted at the given position which uses the
+    /// given number of kibibytes of memory for storing its nodes, managed with
+    /// the given strategy.
+    pub fn new(position: ataxx::Position, memory: Memory, kib: usize) -> Tree {
+        let mut tree = Tree {
             root_pos: position,
-            nodes: cache,
-        }
+            nodes: Nodes::new_kib(memory, kib),
+            root: -1,
+            kib,
+        };
+
+        tree.reset(position);
+        tree
     }
 
-    pub fn pv(&self, node_ptr: NodePtr) -> (Vec<ataxx::Move>, Score) {
-        let node = self.node(node_ptr);
+    /// with_capacity creates a new Tree rooted at the given position which can
+    /// store the given numbers of nodes and edges in total, managed with the
+    /// given strategy, for testing small trees.
+    #[cfg(test)]
+    pub fn with_capacity(
+        position: ataxx::Position,
+        memory: Memory,
+        nodes: usize,
+        edges: usize,
+    ) -> Tree {
+        let nodes = match memory {
+            Memory::Lru => Nodes::Lru(lru::Cache::new(nodes, edges)),
+            Memory::Halves => Nodes::Halves(halves::Halves::new(nodes / 2, edges / 2)),
+        };
 
-        let mut best_edge = -1;
-        let mut best_score = 0.0;
-        for (edge_ptr, edge) in node.edges.iter().enumerate() {
-            if best_edge == -1 || edge.q() > best_score {
-                best_edge = edge_ptr as i32;
-                best_score = edge.q();
+        let mut tree = Tree {
+            root_pos: position,
+            nodes,
+            root: -1,
+            kib: 0,
+        };
+
+        tree.reset(position);
+        tree
+    }
+
+    /// reset clears all the nodes in the Tree, and makes the given position
+    /// the new root. The memory used by the Tree is not reallocated.
+    pub fn reset(&mut self, position: ataxx::Position) {
+        with_nodes!(&mut self.nodes, nodes => nodes.clear());
+        self.root = self.push(Default::default());
+        self.root_pos = position;
+    }
+
+    /// reroot makes the given position the new root of the Tree. If it is
+    /// found within the first two plies of the tree, the statistics of its
+    /// subtree are kept, otherwise the Tree is reset to the given position.
+    pub fn reroot(&mut self, position: ataxx::Position) {
+        let mut frontier = vec![(self.root, self.root_pos)];
+
+        for _ply in 0..=2 {
+            let mut next = vec![];
+
+            for (ptr, pos) in frontier {
+                let same = pos.bitboards == position.bitboards
+                    && pos.side_to_move == position.side_to_move;
+
+                if same {
+                    let root = match &mut self.nodes {
+                        Nodes::Lru(cache) => {
+                            cache.reroot(ptr);
+                            ptr
+                        }
+                        Nodes::Halves(halves) => halves.reroot(ptr),
+                    };
+
+                    // The new root couldn't be kept, as there is no space left
+                    // to copy it into.
+                    if root == -1 {
+                        break;
+                    }
+
+                    self.root = root;
+                    self.root_pos = position;
+                    return;
+                }
+
+                for edge in self.edges(ptr) {
+                    if edge.ptr != -1 {
+                        next.push((edge.ptr, pos.after_move::<true>(edge.mov)));
+                    }
+                }
             }
-        }
 
-        // No edges found in the current node.
-        if best_edge == -1 {
-            return (vec![], 0.0);
+            frontier = next;
         }
 
-        let edge = node.edge(best_edge);
-        if edge.ptr == -1 {
-            (vec![edge.mov], best_score)
+        self.reset(position);
+    }
+
+    /// resize changes the memory budget of the Tree to the given number of
+    /// kibibytes, and the strategy with which it is managed to the given one.
+    /// This also clears all the nodes in the Tree.
+    pub fn resize(&mut self, memory: Memory, kib: usize) {
+        if self.nodes.memory() == memory {
+            with_nodes!(&mut self.nodes, nodes => nodes.resize_kib(kib));
         } else {
-            let (mut child_pv, _score) = self.pv(edge.ptr);
-            let mut pv = vec![edge.mov];
-            pv.append(&mut child_pv);
-            (pv, best_score)
+            // Free the old storage before allocating the new one.
+            self.nodes = Nodes::Lru(lru::Cache::new(0, 0));
+            self.nodes = Nodes::new_kib(memory, kib);
+        }
+
+        self.reset(self.root_pos);
+        self.kib = kib;
+    }
+
+    /// kib returns the memory budget of the Tree in kibibytes.
+    pub fn kib(&self) -> usize {
+        self.kib
+    }
+
+    /// memory returns the strategy with which the Tree manages its memory.
+    pub fn memory(&self) -> Memory {
+        self.nodes.memory()
+    }
+
+    /// pv returns the principal variation from the given node.
+    pub fn pv(&self, node_ptr: NodePtr) -> Vec<ataxx::Move> {
+        let edge = self.edges(node_ptr).iter().reduce(|best, edge| {
+            if edge.rank() > best.rank() {
+                edge
+            } else {
+                best
+            }
+        });
+
+        match edge {
+            Some(edge) => self.line(edge),
+            // No edges found in the current node.
+            None => vec![],
         }
     }
 
+    /// lines returns the given number of best visited moves at the root, best
+    /// first, each along with its principal variation and statistics.
+    pub fn lines(&self, count: usize) -> Vec<Line> {
+        let mut edges: Vec<&Edge> = self
+            .edges(self.root)
+            .iter()
+            .filter(|edge| edge.visits() > 0)
+            .collect();
+
+        edges.sort_by(|a, b| b.rank().partial_cmp(&a.rank()).unwrap());
+
+        edges
+            .into_iter()
+            .take(count)
+            .map(|edge| Line {
+                pv: self.line(edge),
+                score: edge.q(),
+                proof: edge.proof,
+            })
+            .collect()
+    }
+
+    /// line returns the principal variation starting with the given edge.
+    fn line(&self, edge: &Edge) -> Vec<ataxx::Move> {
+        let mut pv = vec![edge.mov];
+        if edge.ptr != -1 {
+            pv.append(&mut self.pv(edge.ptr));
+        }
+
+        pv
+    }
+
     pub fn root_position(&self) -> ataxx::Position {
         self.root_pos
     }
 
+    pub fn root(&self) -> NodePtr {
+        self.root
+    }
+
     pub fn best_move(&self) -> ataxx::Move {
-        let root = self.node(0);
+        self.best_edge(self.root)
+            .map_or(ataxx::Move::NULL, |edge| edge.mov)
+    }
+
+    /// most_visited returns the move at the root with the most visits.
+    pub fn most_visited(&self) -> ataxx::Move {
+        self.edges(self.root)
+            .iter()
+            .max_by_key(|edge| edge.visits())
+            .map_or(ataxx::Move::NULL, |edge| edge.mov)
+    }
+
+    /// ponder_move returns the expected reply to the best move, if the tree
+    /// has been explored deep enough to have one.
+    pub fn ponder_move(&self) -> Option<ataxx::Move> {
+        let edge = self.best_edge(self.root)?;
+        if edge.ptr == -1 {
+            return None;
+        }
+
+        self.best_edge(edge.ptr).map(|edge| edge.mov)
+    }
 
-        let mut best_mov = ataxx::Move::NULL;
-        let mut best_scr = 0.0;
-        for edge in root.edges.iter() {
-            if edge.visits == 0 {
+    fn best_edge(&self, node_ptr: NodePtr) -> Option<&Edge> {
+        let mut best_edge: Option<&Edge> = None;
+        for edge in self.edges(node_ptr) {
+            if edge.visits() == 0 {
                 continue;
             }
 
-            let score = edge.q();
+            if best_edge.is_none_or(|best| edge.rank() > best.rank()) {
+                best_edge = Some(edge);
+            }
+        }
+
+        best_edge
+    }
 
-            if best_mov == ataxx::Move::NULL || score > best_scr {
-                best_mov = edge.mov;
-                best_scr = score;
+    /// node_proof returns the proof of the edge leading to the given node that
+    /// follows from the proofs of the node's own edges, if there is one.
+    pub fn node_proof(&self, node_ptr: NodePtr) -> Proof {
+        if !self.node(node_ptr).expanded() {
+            return Proof::Unknown;
+        }
+
+        let (mut unknown, mut draw, mut longest) = (false, false, 0);
+        let mut shortest: Option<u16> = None;
+        for edge in self.edges(node_ptr) {
+            match edge.proof {
+                // A single winning reply makes the parent's move a loss, and
+                // the quickest of them is the one which will be played.
+                Proof::Win(plies) => shortest = Some(shortest.map_or(plies, |s| s.min(plies))),
+                Proof::Unknown => unknown = true,
+                Proof::Draw => draw = true,
+                // Delay the loss as long as possible.
+                Proof::Loss(plies) => longest = longest.max(plies),
             }
         }
 
-        best_mov
+        if let Some(plies) = shortest {
+            Proof::Loss(plies + 1)
+        } else if unknown {
+            Proof::Unknown
+        } else if draw {
+            Proof::Draw
+        } else {
+            Proof::Win(longest + 1)
+        }
+    }
+}
+
+impl Tree {
+    /// node returns a reference to the Node at the given pointer.
+    pub fn node(&self, ptr: NodePtr) -> &Node {
+        match &self.nodes {
+            Nodes::Lru(cache) => cache.node(ptr),
+            Nodes::Halves(halves) => halves.node(ptr),
+        }
+    }
+
+    /// edges returns the edges of the Node at the given pointer.
+    pub fn edges(&self, ptr: NodePtr) -> &[Edge] {
+        with_nodes!(&self.nodes, nodes => nodes.edges(ptr))
+    }
+
+    pub fn edge(&self, parent: NodePtr, edge_ptr: EdgePtr) -> &Edge {
+        with_nodes!(&self.nodes, nodes => nodes.edge(parent, edge_ptr))
+    }
+
+    pub fn edge_mut(&mut self, parent: NodePtr, edge_ptr: EdgePtr) -> &mut Edge {
+        with_nodes!(&mut self.nodes, nodes => nodes.edge_mut(parent, edge_ptr))
+    }
+
+    /// push adds the given Node to the Tree, and links it to the edge of its
+    /// parent which it was reached by. It returns -1 if no space could be made
+    /// for the node.
+    pub fn push(&mut self, node: Node) -> NodePtr {
+        with_nodes!(&mut self.nodes, nodes => nodes.push(node))
+    }
+
+    /// expand stores the given edges as the edges of the given node. It returns
+    /// false if no space could be made for them.
+    pub fn expand(&mut self, ptr: NodePtr, edges: &[Edge]) -> bool {
+        with_nodes!(&mut self.nodes, nodes => nodes.expand(ptr, edges))
+    }
+
+    /// promote marks the given node as used by a rollout, so that it is kept
+    /// over the nodes which haven't been used as recently. It returns the new
+    /// pointer to the node, which changes if the node had to be moved.
+    pub fn promote(&mut self, ptr: NodePtr) -> NodePtr {
+        with_nodes!(&mut self.nodes, nodes => nodes.promote(ptr))
+    }
+
+    /// pin marks the given node as in use by a search thread, so that it isn't
+    /// purged or moved until it is unpinned.
+    pub fn pin(&self, ptr: NodePtr) {
+        with_nodes!(&self.nodes, nodes => nodes.pin(ptr))
+    }
+
+    /// unpin removes a pin previously placed on the given node by [`Self::pin`].
+    pub fn unpin(&self, ptr: NodePtr) {
+        with_nodes!(&self.nodes, nodes => nodes.unpin(ptr))
+    }
+
+    /// full checks if the Tree needs to be flipped before it can grow any
+    /// further, which only happens with the [Memory::Halves] strategy.
+    pub fn full(&self) -> bool {
+        match &self.nodes {
+            Nodes::Lru(_) => false,
+            Nodes::Halves(halves) => halves.full(),
+        }
+    }
+
+    /// flip makes space in a full Tree by flipping the halves of its memory.
+    /// It must not be called while any of the nodes are pinned.
+    pub fn flip(&mut self) {
+        if let Nodes::Halves(halves) = &mut self.nodes {
+            self.root = halves.flip(self.root);
+        }
     }
 }
 
 impl Tree {
+    /// verify checks the invariants of the Tree, returning the first one which
+    /// is broken. The structure of the node storage is checked first, followed
+    /// by the statistics of every node reachable from the root.
     pub fn verify(&self) -> Result<(), String> {
-        self.verify_node(0, self.root_pos)
+        self.verify_in_flight(0)
+    }
+
+    /// verify_in_flight is like [Self::verify], for a Tree which the given
+    /// number of rollouts are in flight on, whose visits have been counted
+    /// but whose scores haven't been backed up yet.
+    pub fn verify_in_flight(&self, rollouts: usize) -> Result<(), String> {
+        with_nodes!(&self.nodes, nodes => nodes.verify(self.root))?;
+        self.verify_node(self.root, self.root_pos, self.edge(-1, -1), rollouts)
     }
 
-    fn verify_node(&self, ptr: NodePtr, position: ataxx::Position) -> Result<(), String> {
-        let node = self.node(ptr);
+    fn verify_node(
+        &self,
+        ptr: NodePtr,
+        position: ataxx::Position,
+        parent: &Edge,
+        in_flight: usize,
+    ) -> Result<(), String> {
+        let edges = self.edges(ptr);
+        if edges.is_empty() {
+            return Ok(());
+        }
+
+        // Move generation is deterministic, so the edges are the legal moves
+        // of the node's position in the order they are generated in.
+        let moves = position.generate_moves();
+        if edges.len() != moves.len() || edges.iter().zip(moves).any(|(e, m)| e.mov != m) {
+            return Err(format!("edges of node {} aren't its legal moves", ptr));
+        }
+
+        // The first visit to a node only adds it to the tree, and every other
+        // visit continues into at most one of its edges. It isn't continued if
+        // the node is proven, and the node's visits are lost if it is purged.
+        let visits: usize = edges.iter().map(Edge::visits).sum();
+        if visits >= parent.visits() {
+            return Err(format!(
+                "edges of node {} have {} visits, but the node has {}",
+                ptr,
+                visits,
+                parent.visits()
+            ));
+        }
+
+        // Every visit which continued into an edge backed up the complement of
+        // its score to the node, so the scores of the other visits, which all
+        // ended at the node, are each between 0 and 1.
+        let ended = parent.visits() - visits;
+        let ended_scores = edges.iter().fold(parent.scores(), |scores, edge| {
+            scores - (edge.visits() as f64 - edge.scores())
+        });
+        if ended_scores < -(in_flight as f64) - 1e-6 || ended_scores > ended as f64 + 1e-6 {
+            return Err(format!(
+                "visits ending at node {} scored {}, out of [0, {}]",
+                ptr, ended_scores, ended
+            ));
+        }
 
         let mut policy_sum = 0.0;
-        for edge in node.edges.iter() {
-            if !(edge.scores >= 0.0 && edge.scores <= edge.visits as f64) {
+        for edge in edges {
+            if edge.scores() > edge.visits() as f64 {
                 return Err("edge score out of bounds [0, playouts]".to_string());
             }
 
-            policy_sum += edge.policy;
+            policy_sum += edge.policy();
 
             if edge.ptr == -1 {
                 continue;
             }
 
-            self.verify_node(edge.ptr, position.after_move::<true>(edge.mov))?;
+            let position = position.after_move::<true>(edge.mov);
+            self.verify_node(edge.ptr, position, edge, in_flight)?;
         }
 
-        if node.edges.len() > 0 && (1.0 - policy_sum).abs() > 0.00001 {
+        // The quantised policies may each be off by a little from the exact ones.
+        let tolerance = 0.00001 + edges.len() as f64 * Edge::policy_error();
+        if (1.0 - policy_sum).abs() > tolerance {
             return Err(format!("sum of all the policies is {}, not 1", policy_sum));
         }
 
         Ok(())
     }
 }
+
+/// fixtures contains the helpers shared by the tests of the node storages.
+#[cfg(test)]
+mod fixtures {
+    use super::{Edge, Proof};
+
+    /// edges returns the given number of edges, tagged with consecutive ids
+    /// starting from the given one through their proofs.
+    pub fn edges(first: u16, count: u16) -> Vec<Edge> {
+        (first..first + count)
+            .map(|tag| {
+                let mut edge = Edge::new(ataxx::Move::NULL);
+                edge.proof = Proof::Win(tag);
+                edge
+            })
+            .collect()
+    }
+
+    /// tags_of returns the tags of the edges returned by [edges] for the same
+    /// arguments.
+    pub fn tags_of(first: u16, count: u16) -> Vec<Proof> {
+        (first..first + count).map(Proof::Win).collect()
+    }
+
+    /// tags returns the tags of the given edges.
+    pub fn tags(edges: &[Edge]) -> Vec<Proof> {
+        edges.iter().map(|edge| edge.proof).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    /// expanded returns a Tree rooted at the given position whose root has
+    /// been expanded with uniform policies, managed with the given strategy.
+    fn expanded(fen: &str, memory: Memory) -> Tree {
+        let position = ataxx::Position::from_str(fen).unwrap();
+        let mut tree = Tree::with_capacity(position, memory, 64, 1024);
+        let root = tree.root();
+        expand(&mut tree, root, position);
+        tree
+    }
+
+    fn expand(tree: &mut Tree, ptr: NodePtr, position: ataxx::Position) {
+        let mut edges = Edges::new();
+        edges.expand(&position, |_| 0.0, 1.0);
+        assert!(tree.expand(ptr, &edges));
+    }
+
+    /// visit adds the given number of visits with the given total score to the
+    /// given edge of the given node.
+    fn visit(tree: &mut Tree, ptr: NodePtr, edge: EdgePtr, visits: usize, score: f64) {
+        for _ in 0..visits {
+            tree.edge(ptr, edge).add_virtual_loss();
+        }
+
+        tree.edge_mut(ptr, edge).add_score(score);
+    }
+
+    #[test]
+    fn reroot_keeps_subtree() {
+        for memory in [Memory::Lru, Memory::Halves] {
+            let mut tree = expanded("x5o/7/7/7/7/7/o5x x 0 1", memory);
+            let (root, position) = (tree.root(), tree.root_position());
+
+            let mov = tree.edge(root, 3).mov;
+            let child = tree.push(Node::new(root, 3));
+            let next = position.after_move::<true>(mov);
+            expand(&mut tree, child, next);
+
+            let reply = tree.edge(child, 5).mov;
+            let grandchild = tree.push(Node::new(child, 5));
+            let last = next.after_move::<true>(reply);
+
+            tree.reroot(next);
+            assert_eq!(tree.root(), child);
+            assert_eq!(tree.edge(-1, -1).mov, mov);
+
+            tree.reroot(last);
+            assert_eq!(tree.root(), grandchild);
+            assert_eq!(tree.edge(-1, -1).mov, reply);
+            assert_eq!(tree.root_position().checksum, last.checksum);
+            assert_eq!(tree.verify(), Ok(()));
+        }
+    }
+
+    #[test]
+    fn reroot_resets_unknown_positions() {
+        let mut tree = expanded("x5o/7/7/7/7/7/o5x x 0 1", Memory::Lru);
+        visit(&mut tree, -1, -1, 10, 6.0);
+
+        let position = ataxx::Position::from_str("x5o/7/2-1-2/7/2-1-2/7/o5x x 0 1").unwrap();
+        tree.reroot(position);
+
+        assert!(!tree.node(tree.root()).expanded());
+        assert_eq!(tree.edge(-1, -1).visits(), 0);
+        assert_eq!(tree.root_position().checksum, position.checksum);
+        assert_eq!(tree.verify(), Ok(()));
+    }
+
+    #[test]
+    fn lines_rank_edges() {
+        let mut tree = expanded("x5o/7/7/7/7/7/o5x x 0 1", Memory::Lru);
+        let root = tree.root();
+        visit(&mut tree, -1, -1, 40, 20.0);
+
+        visit(&mut tree, root, 0, 10, 7.0);
+        visit(&mut tree, root, 1, 20, 10.0);
+        visit(&mut tree, root, 2, 1, 1.0);
+        visit(&mut tree, root, 3, 5, 0.0);
+        tree.edge_mut(root, 2).proof = Proof::Win(3);
+        tree.edge_mut(root, 3).proof = Proof::Loss(1);
+
+        let moves: Vec<_> = tree.lines(8).iter().map(|line| line.pv[0]).collect();
+        let edge = |i| tree.edge(root, i).mov;
+        assert_eq!(moves, [edge(2), edge(0), edge(1), edge(3)]);
+
+        assert_eq!(tree.best_move(), edge(2));
+        assert_eq!(tree.most_visited(), edge(1));
+        assert_eq!(tree.pv(root), [edge(2)]);
+        assert_eq!(tree.verify(), Ok(()));
+    }
+
+    #[test]
+    fn node_proof() {
+        let mut tree = expanded("x5o/7/7/7/7/7/o5x x 0 1", Memory::Lru);
+        let root = tree.root();
+        let edges = tree.edges(root).len() as EdgePtr;
+
+        assert_eq!(tree.node_proof(root), Proof::Unknown);
+
+        for i in 0..edges {
+            tree.edge_mut(root, i).proof = Proof::Loss(i as u16 + 1);
+        }
+        assert_eq!(tree.node_proof(root), Proof::Win(edges as u16 + 1));
+
+        tree.edge_mut(root, 1).proof = Proof::Draw;
+        assert_eq!(tree.node_proof(root), Proof::Draw);
+
+        tree.edge_mut(root, 2).proof = Proof::Win(4);
+        assert_eq!(tree.node_proof(root), Proof::Loss(5));
+
+        // The quickest win is the one which decides the loss distance,
+        // whichever order the winning edges are in.
+        tree.edge_mut(root, 3).proof = Proof::Win(2);
+        tree.edge_mut(root, 4).proof = Proof::Win(6);
+        assert_eq!(tree.node_proof(root), Proof::Loss(3));
+        tree.edge_mut(root, 3).proof = Proof::Loss(4);
+        tree.edge_mut(root, 4).proof = Proof::Loss(5);
+        assert_eq!(tree.node_proof(root), Proof::Loss(5));
+
+        tree.edge_mut(root, 2).proof = Proof::Unknown;
+        assert_eq!(tree.node_proof(root), Proof::Unknown);
+    }
+
+    #[test]
+    fn verify_catches_broken_statistics() {
+        let mut tree = expanded("x5o/7/7/7/7/7/o5x x 0 1", Memory::Lru);
+        let root = tree.root();
+
+        // The root's edges have more visits than the root itself.
+        visit(&mut tree, root, 0, 1, 0.5);
+        assert!(tree.verify().is_err());
+
+        visit(&mut tree, -1, -1, 2, 1.0);
+        assert_eq!(tree.verify(), Ok(()));
+
+        // An edge can't score more than one point per visit.
+        tree.edge_mut(root, 0).add_score(1.0);
+        assert!(tree.verify().is_err());
+
+        // The visits which continued into an edge have to be backed up to the
+        // node too, with their scores flipped.
+        let mut tree = expanded("x5o/7/7/7/7/7/o5x x 0 1", Memory::Lru);
+        let root = tree.root();
+        visit(&mut tree, -1, -1, 2, 0.0);
+        visit(&mut tree, root, 0, 1, 0.0);
+        assert!(tree.verify().is_err());
+
+        tree.edge(-1, -1).add_score(1.0);
+        assert_eq!(tree.verify(), Ok(()));
+    }
+}
@@ -1,61 +1,105 @@
-use ataxx::MoveStore;
-use derive_new::new;
+use std::ops::Range;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
 
-use super::super::policy;
-use std::slice;
+use derive_more::Deref;
+use derive_new::new;
 
 pub type NodePtr = i32;
 pub type Score = f64;
 
+/// MAX_VISITS is the number of visits of the root edge after which a search is
+/// stopped, which leaves enough room for the visits of the rollouts which are
+/// still in flight before the u32 visit counters overflow.
+pub const MAX_VISITS: usize = (u32::MAX / 2) as usize;
+
+/// SCORE_ONE is the fixed point representation of a score of 1.0. Scores are
+/// accumulated as integers so that the sums are exact, and the same whatever
+/// the order in which the threads back them up.
+const SCORE_ONE: f64 = (1 << 24) as f64;
+
+/// POLICY_ONE is the quantised representation of a policy of 1.0.
+const POLICY_ONE: f64 = u16::MAX as f64;
+
+/// Node is a position in the search tree. Its edges aren't stored in the Node
+/// itself, but in a contiguous range of the edge arena of the tree.
 #[derive(Clone, new)]
 pub struct Node {
-    #[new(value = "Edges::new()")]
-    pub edges: Edges,
+    #[new(value = "0")]
+    first_edge: u32,
+    #[new(value = "0")]
+    edge_count: u16,
+
+    // Number of the Node's edges which are linked to a child node.
+    #[new(value = "0")]
+    pub(super) children: u16,
 
     pub parent_node: NodePtr,
     pub parent_edge: EdgePtr,
 }
 
 impl Node {
-    pub fn expand(&mut self, position: &ataxx::Position, policy: policy::Fn) {
-        position.generate_moves_into(&mut self.edges);
-
-        let mut sum = 0.0;
-        let mut policies = vec![];
-        for edge in self.edges.iter() {
-            let policy = policy(position, edge.mov).exp();
-            policies.push(policy);
-            sum += policy;
-        }
+    pub fn expanded(&self) -> bool {
+        self.edge_count > 0
+    }
 
-        for (i, edge) in self.edges.iter_mut().enumerate() {
-            edge.policy = policies[i] / sum;
-        }
+    /// children returns the number of child nodes of the Node in the tree.
+    pub fn children(&self) -> usize {
+        self.children as usize
     }
-    pub fn edge(&self, ptr: EdgePtr) -> &Edge {
-        &self.edges.edges[ptr as usize]
+
+    /// edges returns the range of the Node's edges in the edge arena.
+    pub fn edges(&self) -> Range<usize> {
+        let first = self.first_edge as usize;
+        first..first + self.edge_count as usize
     }
 
-    pub fn edge_mut(&mut self, ptr: EdgePtr) -> &mut Edge {
-        &mut self.edges.edges[ptr as usize]
+    /// set_edges moves the Node's edges to the given range of the edge arena.
+    pub(super) fn set_edges(&mut self, edges: Range<usize>) {
+        self.first_edge = edges.start as u32;
+        self.edge_count = edges.len() as u16;
     }
+}
 
-    pub fn expanded(&self) -> bool {
-        self.edges.len() > 0
+impl Default for Node {
+    fn default() -> Self {
+        Node::new(-1, -1)
     }
 }
-#[derive(Clone, new)]
+
+/// Edges is a list of freshly generated edges of a node, which haven't been
+/// added to the tree's edge arena yet. It can be reused for expanding nodes
+/// without allocating.
+#[derive(Clone, Deref, new)]
 pub struct Edges {
+    #[deref]
     #[new(value = "vec![]")]
     edges: Vec<Edge>,
+    #[new(value = "vec![]")]
+    policies: Vec<f64>,
 }
 
 impl Edges {
-    pub fn iter(&self) -> slice::Iter<'_, Edge> {
-        self.edges.iter()
-    }
-    pub fn iter_mut(&mut self) -> slice::IterMut<'_, Edge> {
-        self.edges.iter_mut()
+    /// expand replaces the list with the edges of a node, with their policies
+    /// being the softmax of the logits given by the policy at the given
+    /// temperature.
+    pub fn expand<P>(&mut self, position: &ataxx::Position, policy: P, temperature: f64)
+    where
+        P: Fn(ataxx::Move) -> f64,
+    {
+        self.edges.clear();
+        self.policies.clear();
+        position.generate_moves_into(self);
+
+        let mut sum = 0.0;
+        for edge in self.edges.iter() {
+            let policy = (policy(edge.mov) / temperature).exp();
+            self.policies.push(policy);
+            sum += policy;
+        }
+
+        for (edge, policy) in self.edges.iter_mut().zip(&self.policies) {
+            edge.policy = (policy / sum * POLICY_ONE).round() as u16;
+        }
     }
 }
 
@@ -73,35 +117,132 @@ impl ataxx::MoveStore for Edges {
     }
 }
 
-impl Default for Node {
-    fn default() -> Self {
-        Node {
-            edges: Edges::new(),
-            parent_node: -1,
-            parent_edge: -1,
-        }
-    }
-}
-
 pub type EdgePtr = i32;
 
-#[derive(Clone, new)]
+#[derive(new)]
 pub struct Edge {
     pub mov: ataxx::Move,
     #[new(value = "-1")]
     pub ptr: NodePtr,
 
+    // Visits and scores are atomic since they are updated by multiple search
+    // threads which only hold a read lock on the Tree during selection and
+    // backup.
+    #[new(default)]
+    visits: AtomicU32,
+    #[new(default)]
+    scores: AtomicU64,
+
     #[new(value = "0")]
-    pub visits: usize,
-    #[new(value = "0.0")]
-    pub scores: Score,
+    policy: u16,
 
-    #[new(value = "0.0")]
-    pub policy: f64,
+    #[new(default)]
+    pub proof: Proof,
 }
 
 impl Edge {
     pub fn q(&self) -> f64 {
-        self.scores / self.visits.max(1) as f64
+        self.scores() / self.visits().max(1) as f64
+    }
+
+    /// scores returns the sum of the scores backed up to the edge.
+    pub fn scores(&self) -> Score {
+        self.scores.load(Ordering::Relaxed) as f64 / SCORE_ONE
+    }
+
+    /// policy returns the prior probability of the edge's move.
+    pub fn policy(&self) -> f64 {
+        // Multiplying by the reciprocal is a lot faster than dividing.
+        self.policy as f64 * (1.0 / POLICY_ONE)
+    }
+
+    /// policy_error returns the maximum error of a policy due to quantisation.
+    pub fn policy_error() -> f64 {
+        0.5 / POLICY_ONE
+    }
+
+    pub fn visits(&self) -> usize {
+        self.visits.load(Ordering::Relaxed) as usize
+    }
+
+    /// rank returns a key for ordering edges by how good they are for the side
+    /// playing them: proven wins (faster first), then unproven edges and draws
+    /// by their q value, and finally proven losses (slower first).
+    pub fn rank(&self) -> (u8, f64) {
+        match self.proof {
+            Proof::Win(plies) => (2, -(plies as f64)),
+            Proof::Unknown => (1, self.q()),
+            Proof::Draw => (1, 0.5),
+            Proof::Loss(plies) => (0, plies as f64),
+        }
+    }
+
+    /// add_virtual_loss counts a visit to the edge before the score of that
+    /// visit is known, so until it is backed up with [`Edge::add_score`] the
+    /// visit looks like a loss to the other search threads. It returns the
+    /// number of visits the edge had before this one.
+    pub fn add_virtual_loss(&self) -> usize {
+        self.visits.fetch_add(1, Ordering::Relaxed) as usize
+    }
+
+    /// add_score backs up the score of a visit previously counted by
+    /// [`Edge::add_virtual_loss`].
+    pub fn add_score(&self, score: Score) {
+        self.scores
+            .fetch_add((score * SCORE_ONE).round() as u64, Ordering::Relaxed);
+    }
+}
+
+impl Clone for Edge {
+    fn clone(&self) -> Self {
+        Edge {
+            mov: self.mov,
+            ptr: self.ptr,
+            visits: AtomicU32::new(self.visits.load(Ordering::Relaxed)),
+            scores: AtomicU64::new(self.scores.load(Ordering::Relaxed)),
+            policy: self.policy,
+            proof: self.proof,
+        }
+    }
+}
+
+/// Proof is the proven game-theoretic value of an [Edge], from the perspective
+/// of the side playing its move. Wins and losses store the number of plies,
+/// including the edge's move, till the end of the game.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Proof {
+    #[default]
+    Unknown,
+    Win(u16),
+    Loss(u16),
+    Draw,
+}
+
+impl Proof {
+    /// terminal returns the Proof of a move which ends the game by reaching
+    /// the given position.
+    pub fn terminal(position: &ataxx::Position) -> Proof {
+        let winner = position.winner();
+        if winner == ataxx::Piece::None {
+            Proof::Draw
+        } else if winner == position.side_to_move {
+            Proof::Loss(1)
+        } else {
+            Proof::Win(1)
+        }
+    }
+
+    /// score returns the exact score of a proven value.
+    pub fn score(self) -> Option<Score> {
+        match self {
+            Proof::Unknown => None,
+            Proof::Win(_) => Some(1.0),
+            Proof::Loss(_) => Some(0.0),
+            Proof::Draw => Some(0.5),
+        }
+    }
+
+    pub fn is_proven(self) -> bool {
+        self != Proof::Unknown
     }
 }
@@ -1,21 +1,257 @@
-pub type Fn = fn(position: &ataxx::Position) -> f64;
+use std::sync::Arc;
+use std::{fmt, fs};
 
-pub fn eval_to_wdl(eval: f64) -> f64 {
-    1.0 / (1.0 + f64::exp(-eval / 400.0))
+use ataxx::{BitBoard, Piece, Position};
+
+use super::nnue;
+
+/// Value is an evaluator of positions, which scores them from the perspective
+/// of their side to move. Every searching thread keeps its own State for the
+/// position it is at, which is updated incrementally as moves are made.
+pub trait Value: Clone + Send + Sync {
+    type State: Clone + Default + Send + Sync;
+
+    /// state returns the State of the given position, from scratch.
+    fn state(&self, position: &Position) -> Self::State;
+
+    /// update updates the given State of the old position into the one of the
+    /// new position, which is reached by making a move in the old one.
+    fn update(&self, _state: &mut Self::State, _old: &Position, _new: &Position) {}
+
+    /// evaluate returns the evaluation of the given position with the given
+    /// State.
+    fn evaluate(&self, state: &Self::State, position: &Position) -> f64;
+}
+
+/// eval_to_wdl converts the given evaluation into a win probability, with the
+/// scale being the evaluation at which the probability is about 73%.
+pub fn eval_to_wdl(eval: f64, scale: f64) -> f64 {
+    1.0 / (1.0 + f64::exp(-eval / scale))
+}
+
+/// wdl_to_eval is the inverse of [eval_to_wdl].
+pub fn wdl_to_eval(wdl: f64, scale: f64) -> f64 {
+    -scale * f64::ln(1.0 / wdl - 1.0)
+}
+
+/// Material evaluates positions by the difference in the number of pieces.
+#[derive(Clone, Copy, PartialEq)]
+pub struct Material;
+
+impl Value for Material {
+    type State = ();
+
+    fn state(&self, _position: &Position) {}
+
+    fn evaluate(&self, _state: &(), position: &Position) -> f64 {
+        const SCALE: f64 = 12.5;
+        const TEMPO: f64 = SCALE * 4.0;
+
+        let stm = position.side_to_move;
+
+        let stm_piece_n = position.bitboard(stm).cardinality();
+        let xtm_piece_n = position.bitboard(!stm).cardinality();
+
+        stm_piece_n as f64 * SCALE - xtm_piece_n as f64 * SCALE + TEMPO
+    }
+}
+
+/// Handcrafted evaluates positions as the sum of their [terms] times their
+/// given weights.
+#[derive(Clone, Copy, PartialEq)]
+pub struct Handcrafted {
+    pub weights: Weights,
+}
+
+impl Value for Handcrafted {
+    type State = ();
+
+    fn state(&self, _position: &Position) {}
+
+    fn evaluate(&self, _state: &(), position: &Position) -> f64 {
+        terms(position)
+            .iter()
+            .zip(self.weights.vals.iter())
+            .map(|(term, weight)| term * weight)
+            .sum()
+    }
+}
+
+/// A value network's State is the [nnue::Accumulator] of the position.
+impl Value for Arc<nnue::Network> {
+    type State = nnue::Accumulator;
+
+    fn state(&self, position: &Position) -> nnue::Accumulator {
+        self.accumulate(position)
+    }
+
+    fn update(&self, acc: &mut nnue::Accumulator, old: &Position, new: &Position) {
+        nnue::Network::update(self, acc, old, new);
+    }
+
+    fn evaluate(&self, acc: &nnue::Accumulator, position: &Position) -> f64 {
+        nnue::Network::evaluate(self, acc, position.side_to_move)
+    }
+}
+
+/// Selected is the evaluator selected with the engine's options.
+#[derive(Clone)]
+pub enum Selected {
+    Network(Arc<nnue::Network>),
+    Handcrafted(Handcrafted),
+    Material(Material),
 }
 
-pub fn wdl_to_eval(wdl: f64) -> f64 {
-    -400.0 * f64::ln(1.0 / wdl - 1.0)
+/// Selected evaluators are equal if they evaluate positions the same way, with
+/// networks compared by identity as they may be large.
+impl PartialEq for Selected {
+    fn eq(&self, other: &Selected) -> bool {
+        match (self, other) {
+            (Selected::Network(a), Selected::Network(b)) => Arc::ptr_eq(a, b),
+            (Selected::Handcrafted(a), Selected::Handcrafted(b)) => a == b,
+            (Selected::Material(_), Selected::Material(_)) => true,
+            _ => false,
+        }
+    }
+}
+
+impl Value for Selected {
+    type State = nnue::Accumulator;
+
+    fn state(&self, position: &Position) -> nnue::Accumulator {
+        match self {
+            Selected::Network(network) => network.state(position),
+            _ => Default::default(),
+        }
+    }
+
+    fn update(&self, acc: &mut nnue::Accumulator, old: &Position, new: &Position) {
+        if let Selected::Network(network) = self {
+            network.update(acc, old, new);
+        }
+    }
+
+    fn evaluate(&self, acc: &nnue::Accumulator, position: &Position) -> f64 {
+        match self {
+            Selected::Network(network) => network.evaluate(acc, position),
+            Selected::Handcrafted(handcrafted) => handcrafted.evaluate(&(), position),
+            Selected::Material(material) => material.evaluate(&(), position),
+        }
+    }
 }
 
-pub fn material(position: &ataxx::Position) -> f64 {
-    const SCALE: f64 = 12.5;
-    const TEMPO: f64 = SCALE * 4.0;
+/// TERMS is the number of terms of the handcrafted evaluation.
+pub const TERMS: usize = 7;
+
+/// NAMES contains the names of the terms of the handcrafted evaluation.
+pub const NAMES: [&str; TERMS] = [
+    "material", "tempo", "mobility", "safe", "exposed", "parity", "blockers",
+];
 
+/// terms returns the terms of the handcrafted evaluation of the given position
+/// from the perspective of the side to move. Except for the tempo and parity,
+/// each term is the difference between the side to move's and the opponent's
+/// values of a feature.
+pub fn terms(position: &ataxx::Position) -> [f64; TERMS] {
     let stm = position.side_to_move;
 
-    let stm_piece_n = position.bitboard(stm).cardinality();
-    let xtm_piece_n = position.bitboard(!stm).cardinality();
+    let blockers = position.bitboard(Piece::Block);
+    let empty = !(position.bitboard(Piece::Black) | position.bitboard(Piece::White) | blockers);
+
+    let features = |friends: BitBoard, enemies: BitBoard| {
+        [
+            // Number of pieces.
+            friends.cardinality(),
+            // Number of empty squares reachable with singular moves.
+            (friends.singles() & empty).cardinality(),
+            // Number of pieces without any empty neighbours, which can't be
+            // captured until one of their neighbours becomes empty.
+            (friends & !empty.singles()).cardinality(),
+            // Number of pieces which the opponent can capture with a singular
+            // move, into an empty square next to both of them.
+            (friends & (empty & enemies.singles()).singles()).cardinality(),
+            // Number of pieces next to blockers, which are harder to surround.
+            (friends & blockers.singles()).cardinality(),
+        ]
+    };
+
+    let friends = position.bitboard(stm);
+    let enemies = position.bitboard(!stm);
+
+    let [stm_pieces, stm_mobility, stm_safe, stm_exposed, stm_blockers] =
+        features(friends, enemies);
+    let [xtm_pieces, xtm_mobility, xtm_safe, xtm_exposed, xtm_blockers] =
+        features(enemies, friends);
+
+    let diff = |stm: usize, xtm: usize| stm as f64 - xtm as f64;
+
+    [
+        diff(stm_pieces, xtm_pieces),
+        1.0,
+        diff(stm_mobility, xtm_mobility),
+        diff(stm_safe, xtm_safe),
+        diff(stm_exposed, xtm_exposed),
+        // The parity of the number of empty squares decides which side gets
+        // to fill the last square if no one passes.
+        (empty.cardinality() % 2) as f64,
+        diff(stm_blockers, xtm_blockers),
+    ]
+}
+
+/// Weights is the table of the weights of the handcrafted evaluation's terms,
+/// in the same order as their [NAMES].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Weights {
+    pub vals: [f64; TERMS],
+}
+
+impl Weights {
+    /// load reads the Weights from the given file, in the format in which they
+    /// are displayed: one "name, value" line for every term. Terms which are
+    /// missing from the file keep their default weights.
+    pub fn load(path: &str) -> Result<Weights, String> {
+        let file = fs::read_to_string(path).map_err(|err| format!("{}: {}", path, err))?;
+
+        let mut weights = Weights::default();
+        for (i, line) in file.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (name, value) = match line.split_once(',') {
+                Some((name, value)) => (name.trim(), value.trim()),
+                None => return Err(format!("{}:{}: malformed line", path, i + 1)),
+            };
+
+            let term = NAMES
+                .iter()
+                .position(|&term| term == name)
+                .ok_or_else(|| format!("{}:{}: unknown term {:?}", path, i + 1, name))?;
+
+            weights.vals[term] = value
+                .parse()
+                .map_err(|_| format!("{}:{}: bad value {:?}", path, i + 1, value))?;
+        }
+
+        Ok(weights)
+    }
+}
+
+impl Default for Weights {
+    fn default() -> Self {
+        Weights {
+            vals: [12.5, 50.0, 2.0, 3.0, -4.0, 0.0, 1.0],
+        }
+    }
+}
+
+impl fmt::Display for Weights {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (name, val) in NAMES.iter().zip(self.vals.iter()) {
+            writeln!(f, "{}, {}", name, val)?;
+        }
 
-    stm_piece_n as f64 * SCALE - xtm_piece_n as f64 * SCALE + TEMPO
+        Ok(())
+    }
 }
@@ -1,9 +1,93 @@
-use uxi::Parameter;
+use uxi::{BundledCtx, Parameter};
+
+use crate::mcts;
+
+/// PARAM_SCALE is the factor by which search parameters are multiplied to be
+/// represented as integer spin options.
+const PARAM_SCALE: f64 = 1000.0;
 
 pub fn hash() -> Parameter {
     Parameter::Spin(16, 1, 33554432)
 }
 
+pub fn ponder() -> Parameter {
+    Parameter::Check(false)
+}
+
 pub fn threads() -> Parameter {
     Parameter::Spin(1, 1, 1024)
 }
+
+pub fn move_overhead() -> Parameter {
+    Parameter::Spin(10, 0, 5000)
+}
+
+/// DEFAULT_EVAL_FILE is the value of the EvalFile option which selects the
+/// value network embedded in the binary.
+pub const DEFAULT_EVAL_FILE: &str = "<default>";
+
+/// eval_file returns the EvalFile option, whose default is the given file of
+/// the value network selected at startup.
+pub fn eval_file(default: &str) -> Parameter {
+    Parameter::String(default.to_string())
+}
+
+/// NO_POLICY_FILE is the value of the PolicyFile option when no policy network
+/// has been selected.
+pub const NO_POLICY_FILE: &str = "<none>";
+
+pub fn policy_file() -> Parameter {
+    Parameter::String(NO_POLICY_FILE.to_string())
+}
+
+pub fn policy_net() -> Parameter {
+    Parameter::Check(false)
+}
+
+pub fn evaluation() -> Parameter {
+    Parameter::String("network".to_string())
+}
+
+pub fn simulation() -> Parameter {
+    Parameter::String("eval".to_string())
+}
+
+pub fn playout_moves() -> Parameter {
+    Parameter::Spin(4, 1, 1000)
+}
+
+pub fn multipv() -> Parameter {
+    Parameter::Spin(1, 1, 256)
+}
+
+/// param returns the spin option which represents the given search parameter.
+pub fn param(param: &mcts::Param) -> Parameter {
+    Parameter::Spin(
+        param_spin(param.val()),
+        param_spin(param.min()),
+        param_spin(param.max()),
+    )
+}
+
+/// params returns the search parameters with the values set through their
+/// options in the given context.
+pub fn params<T: Send>(ctx: &BundledCtx<T>) -> mcts::Params {
+    let mut params = mcts::Params::new();
+    for param in params.iter_mut() {
+        param.set(param_value(ctx.get_spin_option(param.name()).unwrap()));
+    }
+
+    params
+}
+
+/// param_spin returns the value of the spin option representing a search
+/// parameter with the given value.
+pub fn param_spin(val: f64) -> i64 {
+    (val * PARAM_SCALE).round() as i64
+}
+
+/// param_value returns the value of a search parameter from the value of the
+/// spin option representing it.
+pub fn param_value(spin: i64) -> f64 {
+    spin as f64 / PARAM_SCALE
+}
@@ -0,0 +1,128 @@
+//! texel implements a Texel style tuner for the weights of the handcrafted
+//! evaluation. The weights, along with the scale used to convert evaluations
+//! into win probabilities, are tuned with gradient descent to minimise the log
+//! loss of the predicted win probabilities of a set of labelled positions.
+//!
+//! Scaling the weights and the scale together doesn't change the loss, so the
+//! material weight is pinned to its given value, which fixes the units of the
+//! evaluation and leaves the scale with a single best value.
+use std::thread;
+
+use crate::data;
+use crate::mcts::value::{self, Weights, TERMS};
+
+/// EPOCHS is the number of passes of gradient descent over the data set.
+const EPOCHS: usize = 2000;
+
+/// Adam optimizer hyperparameters.
+const LEARNING_RATE: f64 = 0.1;
+const BETA1: f64 = 0.9;
+const BETA2: f64 = 0.999;
+const EPSILON: f64 = 1e-8;
+
+/// SCALE is the index of the evaluation scale among the tuned variables,
+/// which are the weights followed by the scale.
+const SCALE: usize = TERMS;
+
+/// PINNED is the index of the material weight, which isn't tuned.
+const PINNED: usize = 0;
+
+/// Sample is a labelled position reduced to its evaluation terms.
+struct Sample {
+    terms: [f64; TERMS],
+    result: f64,
+}
+
+/// tune tunes the given weights and evaluation scale on the labelled positions
+/// in the given file, returning the tuned weights and scale.
+pub fn tune(path: &str, weights: Weights, scale: f64) -> Result<(Weights, f64), String> {
+    let samples: Vec<Sample> = data::read(path)?
+        .iter()
+        .map(|entry| Sample {
+            terms: value::terms(&entry.position),
+            result: entry.result,
+        })
+        .collect();
+
+    if samples.is_empty() {
+        return Err(format!("{}: no labelled positions", path));
+    }
+
+    println!("loaded {} positions from {}", samples.len(), path);
+
+    let threads = thread::available_parallelism().map_or(1, |n| n.get());
+    let chunk_size = samples.len().div_ceil(threads);
+
+    let mut vars = [0.0; TERMS + 1];
+    vars[..TERMS].copy_from_slice(&weights.vals);
+    vars[SCALE] = scale;
+
+    let mut m = [0.0; TERMS + 1]; // First moment estimates.
+    let mut v = [0.0; TERMS + 1]; // Second moment estimates.
+
+    for epoch in 1..=EPOCHS {
+        // Compute the loss and its gradient over the data set in parallel.
+        let (loss, grad) = thread::scope(|scope| {
+            let handles: Vec<_> = samples
+                .chunks(chunk_size)
+                .map(|chunk| scope.spawn(|| gradient(chunk, &vars)))
+                .collect();
+
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .fold((0.0, [0.0; TERMS + 1]), |(loss, mut grad), (l, g)| {
+                    grad.iter_mut().zip(g).for_each(|(a, b)| *a += b);
+                    (loss + l, grad)
+                })
+        });
+
+        let n = samples.len() as f64;
+        for i in (0..=TERMS).filter(|&i| i != PINNED) {
+            let g = grad[i] / n;
+
+            m[i] = BETA1 * m[i] + (1.0 - BETA1) * g;
+            v[i] = BETA2 * v[i] + (1.0 - BETA2) * g * g;
+
+            let m_hat = m[i] / (1.0 - BETA1.powi(epoch as i32));
+            let v_hat = v[i] / (1.0 - BETA2.powi(epoch as i32));
+
+            vars[i] -= LEARNING_RATE * m_hat / (v_hat.sqrt() + EPSILON);
+        }
+
+        if epoch == 1 || epoch % 100 == 0 {
+            println!("epoch {} loss {:.6}", epoch, loss / n);
+        }
+    }
+
+    let mut weights = weights;
+    weights.vals.copy_from_slice(&vars[..TERMS]);
+
+    Ok((weights, vars[SCALE]))
+}
+
+/// gradient returns the total log loss of the given samples with the given
+/// variables, along with its gradient with respect to the variables.
+fn gradient(samples: &[Sample], vars: &[f64; TERMS + 1]) -> (f64, [f64; TERMS + 1]) {
+    let scale = vars[SCALE];
+
+    let mut loss = 0.0;
+    let mut grad = [0.0; TERMS + 1];
+    for sample in samples {
+        let eval: f64 = (0..TERMS).map(|i| sample.terms[i] * vars[i]).sum();
+        let wdl = value::eval_to_wdl(eval, scale).clamp(1e-9, 1.0 - 1e-9);
+
+        loss -= sample.result * wdl.ln() + (1.0 - sample.result) * (1.0 - wdl).ln();
+
+        // The derivative of the log loss with respect to eval / scale.
+        let error = wdl - sample.result;
+
+        for (grad, term) in grad.iter_mut().zip(sample.terms) {
+            *grad += error * term / scale;
+        }
+
+        grad[SCALE] -= error * eval / (scale * scale);
+    }
+
+    (loss, grad)
+}
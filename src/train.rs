@@ -0,0 +1,267 @@
+//! train implements a trainer for the value network, which learns the weights
+//! of a [nnue::Network] from a set of labelled positions. The network is trained
+//! in floating point with mini-batch gradient descent, minimising the squared
+//! error between its predicted win probabilities and those of the positions,
+//! and is then quantised into the format loaded by the engine.
+use std::{fs, thread, time};
+
+use ataxx::Piece;
+use rand::seq::SliceRandom;
+use rand::Rng;
+
+use crate::data;
+use crate::mcts::nnue::{self, HIDDEN, INPUTS};
+use crate::mcts::value;
+
+/// EPOCHS is the number of passes over the training set.
+const EPOCHS: usize = 30;
+
+/// BATCH_SIZE is the number of positions in every mini-batch.
+const BATCH_SIZE: usize = 4096;
+
+/// Adam optimizer hyperparameters.
+const LEARNING_RATE: f32 = 0.001;
+const BETA1: f32 = 0.9;
+const BETA2: f32 = 0.999;
+const EPSILON: f32 = 1e-8;
+
+/// VALIDATION is the fraction of the positions which are held out of training
+/// to measure the validation loss.
+const VALIDATION: f64 = 0.1;
+
+/// SCORE_WEIGHT is the weight of the win probability of a position's score in
+/// its target, with the rest coming from the game result.
+const SCORE_WEIGHT: f32 = 0.5;
+
+/// CLIP is the bound on the magnitude of the weights, which keeps them within
+/// the range of i16s once they are quantised.
+const CLIP: f32 = 1.98;
+
+/// Offsets of the sections of the network's weights, which are stored in the
+/// same order as a serialized [nnue::Network].
+const HIDDEN_BIASES: usize = INPUTS * HIDDEN;
+const OUTPUT_WEIGHTS: usize = HIDDEN_BIASES + HIDDEN;
+const OUTPUT_BIAS: usize = OUTPUT_WEIGHTS + 2 * HIDDEN;
+const WEIGHTS: usize = OUTPUT_BIAS + 1;
+
+/// Sample is a labelled position reduced to the network's active inputs, from
+/// the perspective of the side to move followed by the other side's.
+struct Sample {
+    inputs: [Vec<u8>; 2],
+    target: f32,
+}
+
+/// train trains a value network on the labelled positions in the given file,
+/// and writes the quantised network to the given output file.
+pub fn train(path: &str, output: &str, scale: f64) -> Result<(), String> {
+    let mut samples: Vec<Sample> = data::read(path)?
+        .iter()
+        .map(|entry| sample(entry, scale))
+        .collect();
+
+    if samples.len() < 2 {
+        return Err(format!("{}: not enough labelled positions", path));
+    }
+
+    let mut rng = rand::thread_rng();
+    samples.shuffle(&mut rng);
+
+    let validation_n = ((samples.len() as f64 * VALIDATION) as usize).max(1);
+    let (validation, training) = samples.split_at_mut(validation_n);
+
+    println!(
+        "loaded {} training and {} validation positions from {}",
+        training.len(),
+        validation.len(),
+        path
+    );
+
+    // Initialise the weights randomly, with the biases zeroed.
+    let mut weights: Vec<f32> = (0..WEIGHTS)
+        .map(|i| {
+            if (HIDDEN_BIASES..OUTPUT_WEIGHTS).contains(&i) || i == OUTPUT_BIAS {
+                0.0
+            } else {
+                rng.gen_range(-0.1..0.1)
+            }
+        })
+        .collect();
+
+    let mut m = vec![0.0; WEIGHTS]; // First moment estimates.
+    let mut v = vec![0.0; WEIGHTS]; // Second moment estimates.
+
+    let scale = scale as f32;
+    let threads = thread::available_parallelism().map_or(1, |n| n.get());
+
+    let mut step = 0;
+    for epoch in 1..=EPOCHS {
+        let start = time::Instant::now();
+        training.shuffle(&mut rng);
+
+        let mut training_loss = 0.0;
+        for batch in training.chunks(BATCH_SIZE) {
+            let (loss, grad) = gradient(batch, &weights, scale, threads);
+            training_loss += loss;
+
+            step += 1;
+            let n = batch.len() as f32;
+            for i in 0..WEIGHTS {
+                let g = grad[i] / n;
+
+                m[i] = BETA1 * m[i] + (1.0 - BETA1) * g;
+                v[i] = BETA2 * v[i] + (1.0 - BETA2) * g * g;
+
+                let m_hat = m[i] / (1.0 - BETA1.powi(step));
+                let v_hat = v[i] / (1.0 - BETA2.powi(step));
+
+                weights[i] -= LEARNING_RATE * m_hat / (v_hat.sqrt() + EPSILON);
+                weights[i] = weights[i].clamp(-CLIP, CLIP);
+            }
+        }
+
+        let (validation_loss, _) = gradient(validation, &weights, scale, threads);
+
+        println!(
+            "epoch {} training loss {:.6} validation loss {:.6} time {:.1}s",
+            epoch,
+            training_loss / training.len() as f32,
+            validation_loss / validation.len() as f32,
+            start.elapsed().as_secs_f64()
+        );
+    }
+
+    let bytes = quantise(&weights);
+    nnue::Network::from_bytes(&bytes)?;
+
+    fs::write(output, bytes).map_err(|err| format!("{}: {}", output, err))
+}
+
+/// sample reduces the given labelled position to a Sample. The target blends
+/// the win probability of the position's score, if it has one, with the
+/// result of its game.
+fn sample(entry: &data::Entry, scale: f64) -> Sample {
+    let position = &entry.position;
+    let stm = position.side_to_move;
+
+    let inputs = [stm, !stm].map(|perspective| {
+        let mut inputs = vec![];
+        for piece in [Piece::Black, Piece::White] {
+            for sq in position.bitboard(piece) {
+                inputs.push(nnue::input(perspective, piece, sq) as u8);
+            }
+        }
+
+        inputs
+    });
+
+    let result = entry.result as f32;
+    let target = match entry.score {
+        Some(score) => {
+            let wdl = value::eval_to_wdl(score, scale) as f32;
+            SCORE_WEIGHT * wdl + (1.0 - SCORE_WEIGHT) * result
+        }
+        None => result,
+    };
+
+    Sample { inputs, target }
+}
+
+/// gradient returns the total loss of the given samples with the given
+/// weights, along with its gradient with respect to the weights, computed
+/// on the given number of threads.
+fn gradient(samples: &[Sample], weights: &[f32], scale: f32, threads: usize) -> (f32, Vec<f32>) {
+    let chunk_size = samples.len().div_ceil(threads);
+
+    thread::scope(|scope| {
+        let handles: Vec<_> = samples
+            .chunks(chunk_size)
+            .map(|chunk| scope.spawn(|| chunk_gradient(chunk, weights, scale)))
+            .collect();
+
+        handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .fold((0.0, vec![0.0; WEIGHTS]), |(loss, mut grad), (l, g)| {
+                grad.iter_mut().zip(g).for_each(|(a, b)| *a += b);
+                (loss + l, grad)
+            })
+    })
+}
+
+/// chunk_gradient computes the loss and gradient of a chunk of samples on the
+/// current thread.
+fn chunk_gradient(samples: &[Sample], weights: &[f32], scale: f32) -> (f32, Vec<f32>) {
+    let mut loss = 0.0;
+    let mut grad = vec![0.0; WEIGHTS];
+
+    for sample in samples {
+        // Compute the hidden layer from the perspective of both the sides.
+        let mut acc = [[0.0; HIDDEN]; 2];
+        for (acc, inputs) in acc.iter_mut().zip(&sample.inputs) {
+            acc.copy_from_slice(&weights[HIDDEN_BIASES..OUTPUT_WEIGHTS]);
+            for &input in inputs {
+                let offset = input as usize * HIDDEN;
+                for (val, weight) in acc.iter_mut().zip(&weights[offset..offset + HIDDEN]) {
+                    *val += weight;
+                }
+            }
+        }
+
+        let mut output = weights[OUTPUT_BIAS];
+        for (side, acc) in acc.iter().enumerate() {
+            let output_weights = &weights[OUTPUT_WEIGHTS + side * HIDDEN..];
+            for (val, weight) in acc.iter().zip(output_weights) {
+                output += val.clamp(0.0, 1.0) * weight;
+            }
+        }
+
+        let eval = output * nnue::SCALE as f32;
+        let wdl = 1.0 / (1.0 + (-eval / scale).exp());
+
+        loss += (wdl - sample.target).powi(2);
+
+        // The derivative of the loss with respect to the network's output.
+        let error = 2.0 * (wdl - sample.target) * wdl * (1.0 - wdl) * nnue::SCALE as f32 / scale;
+
+        grad[OUTPUT_BIAS] += error;
+        for (side, (acc, inputs)) in acc.iter().zip(&sample.inputs).enumerate() {
+            let offset = OUTPUT_WEIGHTS + side * HIDDEN;
+            for (i, &val) in acc.iter().enumerate() {
+                grad[offset + i] += error * val.clamp(0.0, 1.0);
+
+                // The clipped activation has no gradient outside of (0, 1).
+                if val <= 0.0 || val >= 1.0 {
+                    continue;
+                }
+
+                let error = error * weights[offset + i];
+                grad[HIDDEN_BIASES + i] += error;
+                for &input in inputs {
+                    grad[input as usize * HIDDEN + i] += error;
+                }
+            }
+        }
+    }
+
+    (loss, grad)
+}
+
+/// quantise converts the given weights into a serialized [nnue::Network].
+fn quantise(weights: &[f32]) -> Vec<u8> {
+    weights
+        .iter()
+        .enumerate()
+        .map(|(i, &weight)| {
+            let factor = if i < OUTPUT_WEIGHTS {
+                nnue::QA
+            } else if i < OUTPUT_BIAS {
+                nnue::QB
+            } else {
+                nnue::QA * nnue::QB
+            };
+
+            (weight * factor as f32).round() as i16
+        })
+        .flat_map(i16::to_le_bytes)
+        .collect()
+}
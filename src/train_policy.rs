@@ -0,0 +1,272 @@
+//! train_policy implements a trainer for the policy network, which learns the
+//! weights of a [policy_net::Network] from the visit distributions of searched
+//! positions. The network is trained with mini-batch gradient descent,
+//! minimising the cross-entropy between the visit distribution of a position
+//! and the softmax of the logits of its moves, and is then written in the
+//! format loaded by the engine.
+use std::{fs, thread, time};
+
+use ataxx::{Move, Square};
+use rand::seq::SliceRandom;
+use rand::Rng;
+
+use crate::data;
+use crate::mcts::policy_net::{self, HIDDEN, INPUTS, SOURCES};
+
+/// EPOCHS is the number of passes over the training set.
+const EPOCHS: usize = 30;
+
+/// BATCH_SIZE is the number of positions in every mini-batch.
+const BATCH_SIZE: usize = 4096;
+
+/// Adam optimizer hyperparameters.
+const LEARNING_RATE: f32 = 0.001;
+const BETA1: f32 = 0.9;
+const BETA2: f32 = 0.999;
+const EPSILON: f32 = 1e-8;
+
+/// VALIDATION is the fraction of the positions which are held out of training
+/// to measure the validation loss.
+const VALIDATION: f64 = 0.1;
+
+/// Offsets of the sections of the network's weights, which are stored in the
+/// same order as a serialized [policy_net::Network].
+const HIDDEN_BIASES: usize = INPUTS * HIDDEN;
+const SOURCE_WEIGHTS: usize = HIDDEN_BIASES + HIDDEN;
+const TARGET_WEIGHTS: usize = SOURCE_WEIGHTS + SOURCES * HIDDEN;
+const WEIGHTS: usize = TARGET_WEIGHTS + Square::N * HIDDEN;
+
+/// Sample is a searched position reduced to the network's active inputs, and
+/// the source, target and share of the visits of each of its legal moves.
+struct Sample {
+    inputs: Vec<u8>,
+    moves: Vec<(u8, u8, f32)>,
+}
+
+/// train trains a policy network on the visit distributions of the positions
+/// in the given file, and writes the network to the given output file.
+pub fn train(path: &str, output: &str) -> Result<(), String> {
+    let mut samples = vec![];
+    for entry in data::read(path)? {
+        if let Some(sample) = sample(&entry)? {
+            samples.push(sample);
+        }
+    }
+
+    if samples.len() < 2 {
+        return Err(format!("{}: not enough positions with visits", path));
+    }
+
+    let mut rng = rand::thread_rng();
+    samples.shuffle(&mut rng);
+
+    let validation_n = ((samples.len() as f64 * VALIDATION) as usize).max(1);
+    let (validation, training) = samples.split_at_mut(validation_n);
+
+    println!(
+        "loaded {} training and {} validation positions from {}",
+        training.len(),
+        validation.len(),
+        path
+    );
+
+    // Initialise the weights randomly, with the biases zeroed.
+    let mut weights: Vec<f32> = (0..WEIGHTS)
+        .map(|i| {
+            if (HIDDEN_BIASES..SOURCE_WEIGHTS).contains(&i) {
+                0.0
+            } else {
+                rng.gen_range(-0.1..0.1)
+            }
+        })
+        .collect();
+
+    let mut m = vec![0.0; WEIGHTS]; // First moment estimates.
+    let mut v = vec![0.0; WEIGHTS]; // Second moment estimates.
+
+    let threads = thread::available_parallelism().map_or(1, |n| n.get());
+
+    let mut step = 0;
+    for epoch in 1..=EPOCHS {
+        let start = time::Instant::now();
+        training.shuffle(&mut rng);
+
+        let mut training_loss = 0.0;
+        for batch in training.chunks(BATCH_SIZE) {
+            let (loss, grad) = gradient(batch, &weights, threads);
+            training_loss += loss;
+
+            step += 1;
+            let n = batch.len() as f32;
+            for i in 0..WEIGHTS {
+                let g = grad[i] / n;
+
+                m[i] = BETA1 * m[i] + (1.0 - BETA1) * g;
+                v[i] = BETA2 * v[i] + (1.0 - BETA2) * g * g;
+
+                let m_hat = m[i] / (1.0 - BETA1.powi(step));
+                let v_hat = v[i] / (1.0 - BETA2.powi(step));
+
+                weights[i] -= LEARNING_RATE * m_hat / (v_hat.sqrt() + EPSILON);
+            }
+        }
+
+        let (validation_loss, _) = gradient(validation, &weights, threads);
+
+        println!(
+            "epoch {} training loss {:.6} validation loss {:.6} time {:.1}s",
+            epoch,
+            training_loss / training.len() as f32,
+            validation_loss / validation.len() as f32,
+            start.elapsed().as_secs_f64()
+        );
+    }
+
+    let bytes: Vec<u8> = weights
+        .iter()
+        .flat_map(|weight| weight.to_le_bytes())
+        .collect();
+    policy_net::Network::from_bytes(&bytes)?;
+
+    fs::write(output, bytes).map_err(|err| format!("{}: {}", output, err))
+}
+
+/// sample reduces the given position to a Sample, or returns None if there is
+/// nothing to learn from it: it has no visits, or passing is its only move.
+fn sample(entry: &data::Entry) -> Result<Option<Sample>, String> {
+    let position = &entry.position;
+    let stm = position.side_to_move;
+
+    let total: f32 = entry.visits.iter().map(|&(_, visits)| visits as f32).sum();
+    if total == 0.0 || entry.visits.iter().any(|&(mov, _)| mov == Move::PASS) {
+        return Ok(None);
+    }
+
+    let mut inputs = vec![];
+    for (offset, piece) in [(0, stm), (Square::N, !stm)] {
+        for sq in position.bitboard(piece) {
+            inputs.push((offset + sq as usize) as u8);
+        }
+    }
+
+    let moves: Vec<Move> = position.generate_moves().into_iter().collect();
+    if let Some(&(mov, _)) = entry.visits.iter().find(|(mov, _)| !moves.contains(mov)) {
+        return Err(format!(
+            "{}: visited move {} isn't legal",
+            data::fen(position),
+            mov
+        ));
+    }
+
+    let moves = moves
+        .into_iter()
+        .map(|mov| {
+            let visits = entry
+                .visits
+                .iter()
+                .find(|&&(visited, _)| visited == mov)
+                .map_or(0, |&(_, visits)| visits);
+
+            // Singular moves share a source, like in the network.
+            let source = if mov.is_single() {
+                Square::N
+            } else {
+                mov.source() as usize
+            };
+
+            (source as u8, mov.target() as u8, visits as f32 / total)
+        })
+        .collect();
+
+    Ok(Some(Sample { inputs, moves }))
+}
+
+/// gradient returns the total loss of the given samples with the given
+/// weights, along with its gradient with respect to the weights, computed
+/// on the given number of threads.
+fn gradient(samples: &[Sample], weights: &[f32], threads: usize) -> (f32, Vec<f32>) {
+    let chunk_size = samples.len().div_ceil(threads);
+
+    thread::scope(|scope| {
+        let handles: Vec<_> = samples
+            .chunks(chunk_size)
+            .map(|chunk| scope.spawn(|| chunk_gradient(chunk, weights)))
+            .collect();
+
+        handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .fold((0.0, vec![0.0; WEIGHTS]), |(loss, mut grad), (l, g)| {
+                grad.iter_mut().zip(g).for_each(|(a, b)| *a += b);
+                (loss + l, grad)
+            })
+    })
+}
+
+/// chunk_gradient computes the loss and gradient of a chunk of samples on the
+/// current thread.
+fn chunk_gradient(samples: &[Sample], weights: &[f32]) -> (f32, Vec<f32>) {
+    let mut loss = 0.0;
+    let mut grad = vec![0.0; WEIGHTS];
+
+    // The weights of the given move source or target.
+    let source = |source: u8| SOURCE_WEIGHTS + source as usize * HIDDEN;
+    let target = |target: u8| TARGET_WEIGHTS + target as usize * HIDDEN;
+
+    for sample in samples {
+        let mut hidden = [0.0; HIDDEN];
+        hidden.copy_from_slice(&weights[HIDDEN_BIASES..SOURCE_WEIGHTS]);
+        for &input in &sample.inputs {
+            let offset = input as usize * HIDDEN;
+            for (val, weight) in hidden.iter_mut().zip(&weights[offset..offset + HIDDEN]) {
+                *val += weight;
+            }
+        }
+
+        let activated = hidden.map(|val: f32| val.max(0.0));
+
+        let logits: Vec<f32> = sample
+            .moves
+            .iter()
+            .map(|&(s, t, _)| {
+                (0..HIDDEN)
+                    .map(|i| activated[i] * (weights[source(s) + i] + weights[target(t) + i]))
+                    .sum()
+            })
+            .collect();
+
+        // Compute the softmax of the logits, shifted by the largest of them
+        // so that the exponentials can't overflow.
+        let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+        let exps: Vec<f32> = logits.iter().map(|logit| (logit - max).exp()).collect();
+        let sum: f32 = exps.iter().sum();
+
+        let mut hidden_error = [0.0; HIDDEN];
+        for (&(s, t, share), exp) in sample.moves.iter().zip(exps) {
+            let prob = exp / sum;
+            loss -= share * prob.max(1e-9).ln();
+
+            // The derivative of the loss with respect to the move's logit.
+            let error = prob - share;
+            for i in 0..HIDDEN {
+                grad[source(s) + i] += error * activated[i];
+                grad[target(t) + i] += error * activated[i];
+                hidden_error[i] += error * (weights[source(s) + i] + weights[target(t) + i]);
+            }
+        }
+
+        for (i, error) in hidden_error.into_iter().enumerate() {
+            // The activation has no gradient below zero.
+            if hidden[i] <= 0.0 {
+                continue;
+            }
+
+            grad[HIDDEN_BIASES + i] += error;
+            for &input in &sample.inputs {
+                grad[input as usize * HIDDEN + i] += error;
+            }
+        }
+    }
+
+    (loss, grad)
+}
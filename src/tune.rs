@@ -0,0 +1,79 @@
+//! tune implements the support for tuning the search parameters with SPSA,
+//! by printing them in the OpenBench SPSA input format and loading the tuned
+//! values produced by a tuning session.
+use std::fs;
+
+use crate::{mcts, options};
+
+/// LEARNING_RATE is the SPSA learning rate at the end of a tuning session.
+const LEARNING_RATE: f64 = 0.002;
+
+/// STEPS is the number of SPSA steps at the end of a tuning session which span
+/// the range of a parameter.
+const STEPS: f64 = 20.0;
+
+/// spsa_input returns the given search parameters in the OpenBench SPSA input
+/// format, with one "name, type, value, min, max, step, learning rate" line
+/// for every parameter. The values are scaled like the parameters' options.
+pub fn spsa_input(params: &mcts::Params) -> String {
+    params
+        .iter()
+        .map(|param| {
+            let (val, min, max) = (
+                options::param_spin(param.val()),
+                options::param_spin(param.min()),
+                options::param_spin(param.max()),
+            );
+
+            format!(
+                "{}, int, {}, {}, {}, {}, {}\n",
+                param.name(),
+                val,
+                min,
+                max,
+                ((max - min) as f64 / STEPS).max(1.0),
+                LEARNING_RATE
+            )
+        })
+        .collect()
+}
+
+/// load sets the search parameters to the tuned values in the given file. Each
+/// line of the file is either a "name, value" pair, as output by OpenBench at
+/// the end of a tuning session, or a line of the SPSA input format. Empty lines
+/// and lines starting with a '#' are ignored.
+pub fn load(params: &mut mcts::Params, path: &str) -> Result<(), String> {
+    let file = fs::read_to_string(path).map_err(|err| format!("{}: {}", path, err))?;
+
+    for (i, line) in file.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+        let value = match fields.len() {
+            2 => fields[1],
+            7 => fields[2],
+            _ => return Err(format!("{}:{}: malformed line", path, i + 1)),
+        };
+
+        let value: f64 = value
+            .parse()
+            .map_err(|_| format!("{}:{}: bad value {:?}", path, i + 1, value))?;
+
+        match params.iter_mut().find(|param| param.name() == fields[0]) {
+            Some(param) => param.set(options::param_value(value.round() as i64)),
+            None => {
+                return Err(format!(
+                    "{}:{}: unknown parameter {:?}",
+                    path,
+                    i + 1,
+                    fields[0]
+                ))
+            }
+        }
+    }
+
+    Ok(())
+}